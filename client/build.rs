@@ -1,35 +1,105 @@
 #![feature(option_result_contains)]
 
-use std::{io::Write, path::Path, path::PathBuf};
+use std::path::{Path, PathBuf};
+
+#[path = "src/gfx/shader_diagnostics.rs"]
+#[allow(dead_code)]
+mod shader_diagnostics;
+#[path = "src/gfx/shader_preprocessor.rs"]
+mod shader_preprocessor;
+
+use shader_diagnostics::{format_parse_error, format_validation_error};
+use shader_preprocessor::preprocess_shader;
+
+// Parses and validates a preprocessed shader the same way the runtime does before compiling it
+fn validate_shader(
+    path: &str,
+    source: &str,
+) -> Result<(naga::Module, naga::valid::ModuleInfo), String> {
+    let module =
+        naga::front::wgsl::parse_str(source).map_err(|e| format_parse_error(path, source, &e))?;
+    // The device doesn't request any features that add shader capabilities, so shaders can't
+    // rely on any
+    let module_info = naga::valid::Validator::new(
+        naga::valid::ValidationFlags::all(),
+        naga::valid::Capabilities::empty(),
+    )
+    .validate(&module)
+    .map_err(|e| format_validation_error(path, source, &e))?;
+
+    Ok((module, module_info))
+}
+
+fn write_spirv(
+    output_path: &Path,
+    module: &naga::Module,
+    module_info: &naga::valid::ModuleInfo,
+) -> Result<(), String> {
+    // wgpu reads SPIR-V without adjusting the coordinate space, keep it the same as WGSL's
+    let options = naga::back::spv::Options {
+        flags: naga::back::spv::Options::default().flags
+            - naga::back::spv::WriterFlags::ADJUST_COORDINATE_SPACE,
+        ..Default::default()
+    };
+
+    let words = naga::back::spv::write_vec(module, module_info, &options, None)
+        .map_err(|e| format!("error: Failed to write SPIR-V: {}\n", e))?;
+    std::fs::write(output_path, bytemuck::cast_slice::<u32, u8>(&words))
+        .map_err(|e| format!("error: Failed to write {}: {}\n", output_path.display(), e))
+}
 
 fn main() {
-    let files_in_shader_dir: Vec<PathBuf> = std::fs::read_dir("./shaders")
+    // Directories are scanned recursively, this covers includes too
+    println!("cargo:rerun-if-changed=shaders");
+
+    // Only top level shaders are built, shaders/include holds the files they include
+    let shader_paths: Vec<PathBuf> = std::fs::read_dir("./shaders")
         .unwrap()
         .map_while(|path| path.ok())
         .filter(|path| path.path().is_file())
         .map(|path| path.path())
+        .filter(|path| path.extension().contains(&"wgsl"))
         .collect();
 
-    let shader_paths = files_in_shader_dir.iter().filter(|path| {
-        path.extension().contains(&"wgsl") && !path.file_name().contains(&"common.wgsl")
-    });
+    let shader_output_dir = Path::new("../data/shaders");
+    std::fs::create_dir_all(shader_output_dir).unwrap();
 
-    let common_shader_path = files_in_shader_dir
-        .iter()
-        .find(|path| path.file_name().contains(&"common.wgsl"))
-        .expect("Missing common.wgsl");
-
-    let common_shader_buf = std::fs::read(common_shader_path).unwrap();
+    // Precompiled SPIR-V is only written when asked for, WGSL is always written
+    let emit_spirv = std::env::var_os("CARGO_FEATURE_PRECOMPILED_SPIRV").is_some();
 
+    let mut shader_errors = Vec::new();
     for shader_path in shader_paths {
-        print!("cargo:rerun-if-changed={}", shader_path.to_string_lossy());
+        let file_name = shader_path.file_name().unwrap().to_string_lossy();
+
+        let shader_source = preprocess_shader(&file_name, |file| {
+            let path = Path::new("./shaders").join(file);
+            std::fs::read_to_string(&path)
+                .map_err(|e| format!("Failed to read file {}: {}", path.display(), e))
+        })
+        .unwrap_or_else(|e| panic!("Failed to preprocess {}:\n{}", file_name, e));
 
-        let shader_buf = std::fs::read(&shader_path).unwrap();
+        let output_path = shader_output_dir.join(&*file_name);
+        std::fs::write(&output_path, &shader_source).unwrap();
 
-        let shader_output_path = Path::new("../data").join(shader_path);
+        // Keep going so every broken shader is reported at once, errors in includes only once
+        let path = format!("data/shaders/{}", file_name);
+        let result = validate_shader(&path, &shader_source).and_then(|(module, module_info)| {
+            if emit_spirv {
+                write_spirv(&output_path.with_extension("spv"), &module, &module_info)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = result {
+            if !shader_errors.contains(&e) {
+                shader_errors.push(e);
+            }
+        }
+    }
 
-        let mut output_file = std::fs::File::create(shader_output_path).unwrap();
-        output_file.write(&common_shader_buf[..]).unwrap();
-        output_file.write(&shader_buf[..]).unwrap();
+    if !shader_errors.is_empty() {
+        for shader_error in shader_errors {
+            eprintln!("{}", shader_error);
+        }
+        std::process::exit(1);
     }
 }
@@ -1,2 +0,0 @@
-/*override position_location : u32 = 0;*/
-
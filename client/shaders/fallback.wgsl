@@ -0,0 +1,57 @@
+// Stand-in for shaders that are still compiling or failed to compile. The vertex entrypoints
+// only read the position at location 0.
+
+#include "include/common.wgsl"
+
+struct FallbackVertexOut {
+    @builtin(position) position: vec4<f32>,
+};
+
+fn fallback_vertex_out( position: vec4<f32> ) -> FallbackVertexOut {
+    return FallbackVertexOut( position );
+}
+
+fn fallback_world_vertex_out( position: vec4<f32> ) -> FallbackVertexOut {
+    return fallback_vertex_out(world_params.view_proj * model_params.world * position);
+}
+
+@vertex
+fn vs_fallback_vec2( @location(0) position: vec2<f32> ) -> FallbackVertexOut {
+    return fallback_vertex_out(vec4<f32>(position, 0.0, 1.0));
+}
+
+@vertex
+fn vs_fallback_vec3( @location(0) position: vec3<f32> ) -> FallbackVertexOut {
+    return fallback_vertex_out(vec4<f32>(position, 1.0));
+}
+
+@vertex
+fn vs_fallback_vec4( @location(0) position: vec4<f32> ) -> FallbackVertexOut {
+    return fallback_vertex_out(position);
+}
+
+@vertex
+fn vs_fallback_world_vec2( @location(0) position: vec2<f32> ) -> FallbackVertexOut {
+    return fallback_world_vertex_out(vec4<f32>(position, 0.0, 1.0));
+}
+
+@vertex
+fn vs_fallback_world_vec3( @location(0) position: vec3<f32> ) -> FallbackVertexOut {
+    return fallback_world_vertex_out(vec4<f32>(position, 1.0));
+}
+
+@vertex
+fn vs_fallback_world_vec4( @location(0) position: vec4<f32> ) -> FallbackVertexOut {
+    return fallback_world_vertex_out(position);
+}
+
+// For layouts without a usable position, draws nothing
+@vertex
+fn vs_fallback_empty() -> FallbackVertexOut {
+    return fallback_vertex_out(vec4<f32>(0.0, 0.0, 0.0, 1.0));
+}
+
+@fragment
+fn ps_fallback() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0, 0.0, 1.0, 1.0);
+}
@@ -1,10 +1,18 @@
+#include "include/common.wgsl"
+#include "include/lighting.wgsl"
+#include "include/morphing.wgsl"
+#include "include/skinning.wgsl"
 
+// Locations match VertexAttribute in model.rs, models only store the inputs their shader reads
 struct VertexIn {
     @location(0 /*position_location*/) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
-    @location(2) tangent: vec4<f32>,
-    @location(3) tex_coord_0: vec2<f32>,
-    @location(4) tex_coord_1: vec2<f32>,
+    @location(5) color_0: vec4<f32>,
+};
+
+struct SkinnedVertexIn {
+    @location(0 /*position_location*/) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
     @location(5) color_0: vec4<f32>,
     @location(6) joint_0: vec4<u32>,
     @location(7) weight_0: vec4<f32>,
@@ -12,33 +20,36 @@ struct VertexIn {
 
 struct VertexOut {
     @builtin(position) position: vec4<f32>,
-    @location(0) color: vec4<f32>,
+    @location(0) normal: vec3<f32>,
+    @location(1) color: vec4<f32>,
 };
 
-/*struct WorldParams {
-
-}
-
-@group(0) @binding(0)
-var<uniform> world_params: WorldParams;
-
-struct ModelParams {
+@vertex
+fn vs( in: VertexIn, @builtin(vertex_index) vertex_index: u32 ) -> VertexOut {
+    let morphed = morph_vertex(vertex_index, in.position, in.normal, vec3<f32>(0.0));
 
+    var out = VertexOut();
+    out.position = world_params.view_proj * model_params.world * vec4<f32>(morphed.position, 1.0);
+    out.normal = (model_params.normal_matrix * vec4<f32>(morphed.normal, 0.0)).xyz;
+    out.color = in.color_0;
+    return out;
 }
 
-@group(0) @binding(1)
-var<uniform> model_params: ModelParams;*/
-
-
 @vertex
-fn vs( in: VertexIn ) -> VertexOut {
+fn vs_skinned( in: SkinnedVertexIn, @builtin(vertex_index) vertex_index: u32 ) -> VertexOut {
+    let morphed = morph_vertex(vertex_index, in.position, in.normal, vec3<f32>(0.0));
+    let skin = skin_transform(in.joint_0, in.weight_0);
+
     var out = VertexOut();
-    out.position = vec4<f32>(in.position.x, in.position.y, in.position.z, 0.0);
+    out.position = world_params.view_proj * skin.world * vec4<f32>(morphed.position, 1.0);
+    out.normal = (skin.normal_matrix * vec4<f32>(morphed.normal, 0.0)).xyz;
     out.color = in.color_0;
     return out;
 }
 
 @fragment
 fn ps( in: VertexOut ) -> @location(0) vec4<f32> {
-    return in.color;
-}
\ No newline at end of file
+    let n_dot_l = max(dot(normalize(in.normal), light_direction()), 0.0);
+    let diffuse = in.color.rgb / PI * light_radiance() * n_dot_l;
+    return vec4<f32>(diffuse + world_params.ambient_color.rgb * in.color.rgb, in.color.a);
+}
@@ -0,0 +1,37 @@
+#ifndef COMMON_WGSL
+#define COMMON_WGSL
+
+/*override position_location : u32 = 0;*/
+
+struct WorldParams {
+    view_proj: mat4x4<f32>,
+    camera_position: vec4<f32>,
+    // Direction the light travels in
+    light_direction: vec4<f32>,
+    // rgb color, a intensity
+    light_color: vec4<f32>,
+    ambient_color: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> world_params: WorldParams;
+
+// Matches MAX_MORPH_TARGETS in bindgroup.rs
+let MAX_MORPH_TARGETS: u32 = 64u;
+
+struct ModelParams {
+    world: mat4x4<f32>,
+    normal_matrix: mat4x4<f32>,
+    // Weight of morph target i is morph_weights[i / 4u][i % 4u]
+    morph_weights: array<vec4<f32>, 16>,
+    morph_target_count: u32,
+    morph_first_vertex: u32,
+    morph_first_delta: u32,
+};
+
+@group(1) @binding(0)
+var<uniform> model_params: ModelParams;
+
+let PI: f32 = 3.141592653589793;
+
+#endif
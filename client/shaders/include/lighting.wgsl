@@ -0,0 +1,33 @@
+#ifndef LIGHTING_WGSL
+#define LIGHTING_WGSL
+
+#include "include/common.wgsl"
+
+// Direction towards the light
+fn light_direction() -> vec3<f32> {
+    return normalize(-world_params.light_direction.xyz);
+}
+
+fn light_radiance() -> vec3<f32> {
+    return world_params.light_color.rgb * world_params.light_color.a;
+}
+
+fn distribution_ggx( n_dot_h: f32, roughness: f32 ) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+fn geometry_smith( n_dot_v: f32, n_dot_l: f32, roughness: f32 ) -> f32 {
+    let k = (roughness + 1.0) * (roughness + 1.0) / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+fn fresnel_schlick( cos_theta: f32, f0: vec3<f32> ) -> vec3<f32> {
+    return f0 + (vec3<f32>(1.0, 1.0, 1.0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+#endif
@@ -0,0 +1,48 @@
+#ifndef MORPHING_WGSL
+#define MORPHING_WGSL
+
+#include "include/common.wgsl"
+
+// Matches MORPH_TEXTURE_WIDTH in texture.rs
+let MORPH_TEXTURE_WIDTH: u32 = 2048u;
+
+// Position, normal and tangent deltas of every morph target of every vertex of the mesh, vertex by
+// vertex and target by target within a vertex
+@group(1) @binding(1)
+var morph_delta_texture: texture_2d<f32>;
+
+struct MorphedVertex {
+    position: vec3<f32>,
+    normal: vec3<f32>,
+    tangent: vec3<f32>,
+};
+
+fn load_morph_delta( index: u32 ) -> vec3<f32> {
+    let coords = vec2<i32>(i32(index % MORPH_TEXTURE_WIDTH), i32(index / MORPH_TEXTURE_WIDTH));
+    return textureLoad(morph_delta_texture, coords, 0).xyz;
+}
+
+// Runs before skinning, the deltas are in the mesh's bind pose
+fn morph_vertex( vertex_index: u32, position: vec3<f32>, normal: vec3<f32>, tangent: vec3<f32> ) -> MorphedVertex {
+    var out = MorphedVertex( position, normal, tangent );
+
+    let target_count = min(model_params.morph_target_count, MAX_MORPH_TARGETS);
+    let first_delta = model_params.morph_first_delta +
+        (vertex_index - model_params.morph_first_vertex) * target_count * 3u;
+
+    for (var i = 0u; i < target_count; i = i + 1u) {
+        let weight = model_params.morph_weights[i / 4u][i % 4u];
+        if (weight == 0.0) {
+            continue;
+        }
+
+        let delta = first_delta + i * 3u;
+        out.position = out.position + load_morph_delta(delta) * weight;
+        out.normal = out.normal + load_morph_delta(delta + 1u) * weight;
+        out.tangent = out.tangent + load_morph_delta(delta + 2u) * weight;
+    }
+
+    return out;
+}
+
+#endif
@@ -0,0 +1,40 @@
+#ifndef SKINNING_WGSL
+#define SKINNING_WGSL
+
+#include "include/common.wgsl"
+
+// Matches MAX_JOINTS in bindgroup.rs
+let MAX_JOINTS: u32 = 128u;
+
+struct SkinParams {
+    // Joint world transforms times their inverse bind matrices
+    joint_matrices: array<mat4x4<f32>, 128>,
+};
+
+@group(3) @binding(0)
+var<uniform> skin_params: SkinParams;
+
+struct SkinTransform {
+    world: mat4x4<f32>,
+    normal_matrix: mat4x4<f32>,
+};
+
+// Skinned vertices ignore the model's world transform, the joints place them in the world.
+// Vertices without joint weights use the model's transforms.
+fn skin_transform( joints: vec4<u32>, weights: vec4<f32> ) -> SkinTransform {
+    if (dot(weights, vec4<f32>(1.0, 1.0, 1.0, 1.0)) <= 0.0) {
+        return SkinTransform( model_params.world, model_params.normal_matrix );
+    }
+
+    let clamped_joints = min(joints, vec4<u32>(MAX_JOINTS - 1u));
+    let world =
+        skin_params.joint_matrices[clamped_joints.x] * weights.x +
+        skin_params.joint_matrices[clamped_joints.y] * weights.y +
+        skin_params.joint_matrices[clamped_joints.z] * weights.z +
+        skin_params.joint_matrices[clamped_joints.w] * weights.w;
+
+    // Joints are expected to be scaled uniformly, so normals are transformed like positions
+    return SkinTransform( world, world );
+}
+
+#endif
@@ -1,5 +1,5 @@
 struct VertexIn {
-    @location(0 /*position_location*/) position: vec4<f32>,
+    @location(0 /*position_location*/) position: vec3<f32>,
     @location(1) color : vec4<f32>,
 };
 
@@ -10,7 +10,7 @@ struct VertexOut {
 
 @vertex
 fn vs_passthrough( in: VertexIn ) -> VertexOut {
-    return VertexOut( in.position, in.color );
+    return VertexOut( vec4<f32>(in.position, 1.0), in.color );
 }
 
 @fragment
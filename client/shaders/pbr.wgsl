@@ -0,0 +1,180 @@
+#include "include/common.wgsl"
+#include "include/lighting.wgsl"
+#include "include/morphing.wgsl"
+#include "include/skinning.wgsl"
+
+// Locations match VertexAttribute in model.rs, models only store the inputs their shader reads
+struct VertexIn {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) tangent: vec4<f32>,
+    @location(3) tex_coord_0: vec2<f32>,
+    @location(4) tex_coord_1: vec2<f32>,
+    @location(5) color_0: vec4<f32>,
+};
+
+struct SkinnedVertexIn {
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) tangent: vec4<f32>,
+    @location(3) tex_coord_0: vec2<f32>,
+    @location(4) tex_coord_1: vec2<f32>,
+    @location(5) color_0: vec4<f32>,
+    @location(6) joint_0: vec4<u32>,
+    @location(7) weight_0: vec4<f32>,
+};
+
+struct VertexOut {
+    @builtin(position) position: vec4<f32>,
+    @location(0) world_position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(2) tangent: vec4<f32>,
+    @location(3) tex_coord_0: vec2<f32>,
+    @location(4) color: vec4<f32>,
+    @location(5) tex_coord_1: vec2<f32>,
+};
+
+struct MaterialParams {
+    base_color_factor: vec4<f32>,
+    emissive_factor: vec3<f32>,
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+    // Texture coordinate set each texture is sampled with
+    base_color_tex_coord: u32,
+    metallic_roughness_tex_coord: u32,
+    normal_tex_coord: u32,
+    occlusion_tex_coord: u32,
+    emissive_tex_coord: u32,
+};
+
+@group(2) @binding(0)
+var<uniform> material_params: MaterialParams;
+@group(2) @binding(1)
+var base_color_texture: texture_2d<f32>;
+@group(2) @binding(2)
+var base_color_sampler: sampler;
+@group(2) @binding(3)
+var metallic_roughness_texture: texture_2d<f32>;
+@group(2) @binding(4)
+var metallic_roughness_sampler: sampler;
+@group(2) @binding(5)
+var normal_texture: texture_2d<f32>;
+@group(2) @binding(6)
+var normal_sampler: sampler;
+@group(2) @binding(7)
+var occlusion_texture: texture_2d<f32>;
+@group(2) @binding(8)
+var occlusion_sampler: sampler;
+@group(2) @binding(9)
+var emissive_texture: texture_2d<f32>;
+@group(2) @binding(10)
+var emissive_sampler: sampler;
+
+let ALPHA_MODE_OPAQUE: u32 = 0u;
+let ALPHA_MODE_MASK: u32 = 1u;
+
+fn material_tex_coord(tex_coord: u32, in: VertexOut) -> vec2<f32> {
+    return select(in.tex_coord_0, in.tex_coord_1, tex_coord == 1u);
+}
+
+@vertex
+fn vs( in: VertexIn, @builtin(vertex_index) vertex_index: u32 ) -> VertexOut {
+    let morphed = morph_vertex(vertex_index, in.position, in.normal, in.tangent.xyz);
+    let world_position = model_params.world * vec4<f32>(morphed.position, 1.0);
+
+    var out = VertexOut();
+    out.position = world_params.view_proj * world_position;
+    out.world_position = world_position.xyz;
+    out.normal = (model_params.normal_matrix * vec4<f32>(morphed.normal, 0.0)).xyz;
+    out.tangent = vec4<f32>((model_params.world * vec4<f32>(morphed.tangent, 0.0)).xyz, in.tangent.w);
+    out.tex_coord_0 = in.tex_coord_0;
+    out.tex_coord_1 = in.tex_coord_1;
+    out.color = in.color_0;
+    return out;
+}
+
+@vertex
+fn vs_skinned( in: SkinnedVertexIn, @builtin(vertex_index) vertex_index: u32 ) -> VertexOut {
+    let morphed = morph_vertex(vertex_index, in.position, in.normal, in.tangent.xyz);
+    let skin = skin_transform(in.joint_0, in.weight_0);
+    let world_position = skin.world * vec4<f32>(morphed.position, 1.0);
+
+    var out = VertexOut();
+    out.position = world_params.view_proj * world_position;
+    out.world_position = world_position.xyz;
+    out.normal = (skin.normal_matrix * vec4<f32>(morphed.normal, 0.0)).xyz;
+    out.tangent = vec4<f32>((skin.world * vec4<f32>(morphed.tangent, 0.0)).xyz, in.tangent.w);
+    out.tex_coord_0 = in.tex_coord_0;
+    out.tex_coord_1 = in.tex_coord_1;
+    out.color = in.color_0;
+    return out;
+}
+
+@fragment
+fn ps( in: VertexOut, @builtin(front_facing) front_facing: bool ) -> @location(0) vec4<f32> {
+    // Sample everything up front, derivatives are undefined after discard or in divergent branches
+    let base_color_sample = textureSample(base_color_texture, base_color_sampler, material_tex_coord(material_params.base_color_tex_coord, in));
+    let metallic_roughness_sample = textureSample(metallic_roughness_texture, metallic_roughness_sampler, material_tex_coord(material_params.metallic_roughness_tex_coord, in));
+    let normal_sample = textureSample(normal_texture, normal_sampler, material_tex_coord(material_params.normal_tex_coord, in));
+    let occlusion_sample = textureSample(occlusion_texture, occlusion_sampler, material_tex_coord(material_params.occlusion_tex_coord, in));
+    let emissive_sample = textureSample(emissive_texture, emissive_sampler, material_tex_coord(material_params.emissive_tex_coord, in));
+
+    let base_color = material_params.base_color_factor * in.color * base_color_sample;
+
+    var alpha = base_color.a;
+    if (material_params.alpha_mode == ALPHA_MODE_MASK) {
+        if (alpha < material_params.alpha_cutoff) {
+            discard;
+        }
+        alpha = 1.0;
+    } else if (material_params.alpha_mode == ALPHA_MODE_OPAQUE) {
+        alpha = 1.0;
+    }
+
+    let metallic = clamp(material_params.metallic_factor * metallic_roughness_sample.b, 0.0, 1.0);
+    let roughness = clamp(material_params.roughness_factor * metallic_roughness_sample.g, 0.04, 1.0);
+
+    var normal = normalize(in.normal);
+    if (!front_facing) {
+        normal = -normal;
+    }
+
+    // Meshes without tangents skip normal mapping
+    if (dot(in.tangent.xyz, in.tangent.xyz) > 0.0) {
+        let tangent = normalize(in.tangent.xyz - normal * dot(normal, in.tangent.xyz));
+        let bitangent = cross(normal, tangent) * in.tangent.w;
+        let tangent_normal = normal_sample.xyz * 2.0 - vec3<f32>(1.0, 1.0, 1.0);
+        let scaled_normal = vec3<f32>(tangent_normal.xy * material_params.normal_scale, tangent_normal.z);
+        normal = normalize(mat3x3<f32>(tangent, bitangent, normal) * scaled_normal);
+    }
+
+    let view_dir = normalize(world_params.camera_position.xyz - in.world_position);
+    let light_dir = light_direction();
+    let half_dir = normalize(view_dir + light_dir);
+
+    let n_dot_l = max(dot(normal, light_dir), 0.0);
+    let n_dot_v = max(dot(normal, view_dir), 0.0001);
+    let n_dot_h = max(dot(normal, half_dir), 0.0);
+    let v_dot_h = max(dot(view_dir, half_dir), 0.0);
+
+    let f0 = vec3<f32>(0.04, 0.04, 0.04) * (1.0 - metallic) + base_color.rgb * metallic;
+    let f = fresnel_schlick(v_dot_h, f0);
+    let d = distribution_ggx(n_dot_h, roughness);
+    let g = geometry_smith(n_dot_v, n_dot_l, roughness);
+
+    let specular = f * (d * g / (4.0 * n_dot_v * max(n_dot_l, 0.0001)));
+    let diffuse = (vec3<f32>(1.0, 1.0, 1.0) - f) * (1.0 - metallic) * base_color.rgb / PI;
+    let radiance = light_radiance();
+
+    let occlusion = 1.0 + material_params.occlusion_strength * (occlusion_sample.r - 1.0);
+
+    var color = (diffuse + specular) * radiance * n_dot_l;
+    color = color + world_params.ambient_color.rgb * base_color.rgb * occlusion;
+    color = color + material_params.emissive_factor * emissive_sample.rgb;
+
+    return vec4<f32>(color, alpha);
+}
@@ -0,0 +1,459 @@
+use std::ops::{Add, Mul};
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    // Outputs hold an in-tangent, a value and an out-tangent per keyframe
+    CubicSpline,
+}
+
+pub enum ChannelOutputs {
+    Translations(Vec<na::Vector3<f32>>),
+    Rotations(Vec<na::Quaternion<f32>>),
+    Scales(Vec<na::Vector3<f32>>),
+    // One weight per morph target of the node's mesh per output
+    MorphTargetWeights(Vec<f32>),
+}
+
+pub struct AnimationChannel {
+    pub node: usize,
+    pub interpolation: Interpolation,
+    // Keyframe times in seconds, in increasing order
+    pub inputs: Vec<f32>,
+    pub outputs: ChannelOutputs,
+}
+
+pub struct Animation {
+    pub name: String,
+    pub channels: Vec<AnimationChannel>,
+    pub duration: f32,
+}
+
+// Value of an animated node property, applied to the node's TRS
+pub enum ChannelValue {
+    Translation(na::Vector3<f32>),
+    Rotation(na::UnitQuaternion<f32>),
+    Scale(na::Vector3<f32>),
+    MorphWeights(Vec<f32>),
+}
+
+struct Keyframes {
+    previous: usize,
+    next: usize,
+    // Position between the two keyframes, 0 to 1
+    t: f32,
+    // Time between the two keyframes
+    delta: f32,
+}
+
+impl AnimationChannel {
+    fn find_keyframes(&self, time: f32) -> Keyframes {
+        let next = self.inputs.partition_point(|input| *input <= time);
+
+        // Clamp to the first and last keyframes outside the animated range
+        if next == 0 || next == self.inputs.len() {
+            let keyframe = next.min(self.inputs.len() - 1);
+            return Keyframes {
+                previous: keyframe,
+                next: keyframe,
+                t: 0.0,
+                delta: 0.0,
+            };
+        }
+
+        let previous = next - 1;
+        let delta = self.inputs[next] - self.inputs[previous];
+        Keyframes {
+            previous,
+            next,
+            t: if delta > 0.0 {
+                (time - self.inputs[previous]) / delta
+            } else {
+                0.0
+            },
+            delta,
+        }
+    }
+
+    // `value` returns output i
+    fn sample_values<T, F>(&self, value: F, keyframes: &Keyframes) -> T
+    where
+        T: Copy + Add<Output = T> + Mul<f32, Output = T>,
+        F: Fn(usize) -> T,
+    {
+        let Keyframes {
+            previous,
+            next,
+            t,
+            delta,
+        } = *keyframes;
+
+        match self.interpolation {
+            Interpolation::Step => value(previous),
+            Interpolation::Linear => value(previous) * (1.0 - t) + value(next) * t,
+            Interpolation::CubicSpline => {
+                let (t2, t3) = (t * t, t * t * t);
+                let value_0 = value(previous * 3 + 1);
+                let out_tangent_0 = value(previous * 3 + 2);
+                let in_tangent_1 = value(next * 3);
+                let value_1 = value(next * 3 + 1);
+
+                value_0 * (2.0 * t3 - 3.0 * t2 + 1.0)
+                    + out_tangent_0 * ((t3 - 2.0 * t2 + t) * delta)
+                    + value_1 * (-2.0 * t3 + 3.0 * t2)
+                    + in_tangent_1 * ((t3 - t2) * delta)
+            }
+        }
+    }
+
+    fn sample_rotation(
+        &self,
+        rotations: &[na::Quaternion<f32>],
+        keyframes: &Keyframes,
+    ) -> na::UnitQuaternion<f32> {
+        if self.interpolation != Interpolation::Linear {
+            return na::UnitQuaternion::new_normalize(
+                self.sample_values(|index| rotations[index], keyframes),
+            );
+        }
+
+        let from = rotations[keyframes.previous];
+        // Take the shortest path
+        let to = match from.dot(&rotations[keyframes.next]) < 0.0 {
+            true => -rotations[keyframes.next],
+            false => rotations[keyframes.next],
+        };
+
+        let from = na::UnitQuaternion::new_normalize(from);
+        let to = na::UnitQuaternion::new_normalize(to);
+        from.try_slerp(&to, keyframes.t, 1.0e-6)
+            .unwrap_or_else(|| from.nlerp(&to, keyframes.t))
+    }
+
+    fn output_count(&self) -> usize {
+        match self.interpolation {
+            Interpolation::CubicSpline => self.inputs.len() * 3,
+            _ => self.inputs.len(),
+        }
+    }
+
+    pub fn sample(&self, time: f32) -> ChannelValue {
+        let keyframes = self.find_keyframes(time);
+
+        match &self.outputs {
+            ChannelOutputs::Translations(translations) => ChannelValue::Translation(
+                self.sample_values(|index| translations[index], &keyframes),
+            ),
+            ChannelOutputs::Rotations(rotations) => {
+                ChannelValue::Rotation(self.sample_rotation(rotations, &keyframes))
+            }
+            ChannelOutputs::Scales(scales) => {
+                ChannelValue::Scale(self.sample_values(|index| scales[index], &keyframes))
+            }
+            ChannelOutputs::MorphTargetWeights(weights) => {
+                let target_count = weights.len() / self.output_count();
+                ChannelValue::MorphWeights(
+                    (0..target_count)
+                        .map(|target| {
+                            self.sample_values(
+                                |index| weights[index * target_count + target],
+                                &keyframes,
+                            )
+                        })
+                        .collect(),
+                )
+            }
+        }
+    }
+}
+
+impl Animation {
+    pub fn from_gltf(
+        path: &str,
+        animation: &gltf::Animation,
+        gltf_buffers: &[gltf::buffer::Data],
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let name = animation.name().map_or(
+            format!("{} [animation {}]", path, animation.index()),
+            str::to_string,
+        );
+
+        let mut channels = Vec::new();
+        for channel in animation.channels() {
+            let reader = channel.reader(|buffer| Some(&gltf_buffers[buffer.index()]));
+            let morph_target_count = channel
+                .target()
+                .node()
+                .mesh()
+                .and_then(|mesh| mesh.primitives().next())
+                .map_or(0, |primitive| primitive.morph_targets().count());
+
+            let inputs: Vec<f32> = reader
+                .read_inputs()
+                .ok_or(format!("Animation channel has no inputs: {}", name))?
+                .collect();
+
+            let outputs = match reader
+                .read_outputs()
+                .ok_or(format!("Animation channel has no outputs: {}", name))?
+            {
+                gltf::animation::util::ReadOutputs::Translations(translations) => {
+                    ChannelOutputs::Translations(translations.map(na::Vector3::from).collect())
+                }
+                gltf::animation::util::ReadOutputs::Rotations(rotations) => {
+                    ChannelOutputs::Rotations(
+                        rotations
+                            .into_f32()
+                            .map(|[x, y, z, w]| na::Quaternion::new(w, x, y, z))
+                            .collect(),
+                    )
+                }
+                gltf::animation::util::ReadOutputs::Scales(scales) => {
+                    ChannelOutputs::Scales(scales.map(na::Vector3::from).collect())
+                }
+                gltf::animation::util::ReadOutputs::MorphTargetWeights(weights) => {
+                    ChannelOutputs::MorphTargetWeights(weights.into_f32().collect())
+                }
+            };
+
+            let interpolation = match channel.sampler().interpolation() {
+                gltf::animation::Interpolation::Step => Interpolation::Step,
+                gltf::animation::Interpolation::Linear => Interpolation::Linear,
+                gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+            };
+
+            let channel = AnimationChannel {
+                node: channel.target().node().index(),
+                interpolation,
+                inputs,
+                outputs,
+            };
+
+            let (value_count, values_per_output) = match &channel.outputs {
+                ChannelOutputs::Translations(values) | ChannelOutputs::Scales(values) => {
+                    (values.len(), 1)
+                }
+                ChannelOutputs::Rotations(values) => (values.len(), 1),
+                // Weights are grouped by output, each output has a weight per morph target
+                ChannelOutputs::MorphTargetWeights(weights) => {
+                    (weights.len(), weights.len() / channel.output_count().max(1))
+                }
+            };
+            if channel.inputs.is_empty()
+                || values_per_output == 0
+                || value_count != channel.output_count() * values_per_output
+            {
+                Err(format!(
+                    "Animation channel has {} outputs for {} inputs: {}",
+                    value_count,
+                    channel.inputs.len(),
+                    name
+                ))?
+            }
+
+            // Sampled weights replace all of the node's morph weights
+            if let ChannelOutputs::MorphTargetWeights(_) = &channel.outputs {
+                if values_per_output != morph_target_count {
+                    Err(format!(
+                        "Animation channel has {} weights per output for a mesh with {} morph targets: {}",
+                        values_per_output, morph_target_count, name
+                    ))?
+                }
+            }
+
+            channels.push(channel);
+        }
+
+        let duration = channels
+            .iter()
+            .filter_map(|channel| channel.inputs.last())
+            .fold(0.0, |duration: f32, time| duration.max(*time));
+
+        Ok(Self {
+            name,
+            channels,
+            duration,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn translation_channel(
+        interpolation: Interpolation,
+        inputs: Vec<f32>,
+        outputs: &[f32],
+    ) -> AnimationChannel {
+        AnimationChannel {
+            node: 0,
+            interpolation,
+            inputs,
+            outputs: ChannelOutputs::Translations(
+                outputs
+                    .iter()
+                    .map(|x| na::Vector3::new(*x, 0.0, 0.0))
+                    .collect(),
+            ),
+        }
+    }
+
+    fn sample_translation(channel: &AnimationChannel, time: f32) -> f32 {
+        match channel.sample(time) {
+            ChannelValue::Translation(translation) => translation.x,
+            _ => panic!("Expected a translation"),
+        }
+    }
+
+    fn sample_weights(channel: &AnimationChannel, time: f32) -> Vec<f32> {
+        match channel.sample(time) {
+            ChannelValue::MorphWeights(weights) => weights,
+            _ => panic!("Expected morph weights"),
+        }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!(
+            (value - expected).abs() < 1.0e-5,
+            "{} isn't {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn step_holds_the_previous_keyframe() {
+        let channel =
+            translation_channel(Interpolation::Step, vec![0.0, 1.0, 2.0], &[1.0, 2.0, 4.0]);
+
+        assert_near(sample_translation(&channel, 0.0), 1.0);
+        assert_near(sample_translation(&channel, 0.99), 1.0);
+        assert_near(sample_translation(&channel, 1.0), 2.0);
+        assert_near(sample_translation(&channel, 1.5), 2.0);
+    }
+
+    #[test]
+    fn linear_interpolates_between_keyframes() {
+        let channel =
+            translation_channel(Interpolation::Linear, vec![0.0, 1.0, 3.0], &[1.0, 2.0, 4.0]);
+
+        assert_near(sample_translation(&channel, 0.25), 1.25);
+        assert_near(sample_translation(&channel, 1.0), 2.0);
+        assert_near(sample_translation(&channel, 2.0), 3.0);
+    }
+
+    #[test]
+    fn cubic_spline_uses_values_and_scaled_tangents() {
+        // In-tangent, value and out-tangent per keyframe
+        let channel = translation_channel(
+            Interpolation::CubicSpline,
+            vec![0.0, 2.0],
+            &[9.0, 0.0, 0.5, 0.5, 1.0, 9.0],
+        );
+
+        assert_near(sample_translation(&channel, 0.0), 0.0);
+        assert_near(sample_translation(&channel, 2.0), 1.0);
+        // Tangents of 0.5 per second over 2 seconds make the spline a straight line
+        assert_near(sample_translation(&channel, 0.5), 0.25);
+        assert_near(sample_translation(&channel, 1.0), 0.5);
+
+        let flat_channel = translation_channel(
+            Interpolation::CubicSpline,
+            vec![0.0, 1.0],
+            &[9.0, 0.0, 0.0, 0.0, 1.0, 9.0],
+        );
+        assert_near(sample_translation(&flat_channel, 0.25), 0.15625);
+    }
+
+    #[test]
+    fn sampling_clamps_outside_the_keyframes() {
+        for interpolation in [Interpolation::Step, Interpolation::Linear] {
+            let channel = translation_channel(interpolation, vec![1.0, 2.0], &[3.0, 5.0]);
+            assert_near(sample_translation(&channel, 0.0), 3.0);
+            assert_near(sample_translation(&channel, 2.5), 5.0);
+        }
+
+        let channel = translation_channel(
+            Interpolation::CubicSpline,
+            vec![1.0, 2.0],
+            &[9.0, 3.0, 9.0, 9.0, 5.0, 9.0],
+        );
+        assert_near(sample_translation(&channel, 0.0), 3.0);
+        assert_near(sample_translation(&channel, 2.5), 5.0);
+    }
+
+    #[test]
+    fn single_keyframe_is_constant() {
+        let channel = translation_channel(Interpolation::Linear, vec![1.0], &[3.0]);
+        assert_near(sample_translation(&channel, 0.0), 3.0);
+        assert_near(sample_translation(&channel, 1.0), 3.0);
+        assert_near(sample_translation(&channel, 2.0), 3.0);
+    }
+
+    #[test]
+    fn linear_rotation_takes_the_shortest_path() {
+        let quarter_turn = na::UnitQuaternion::from_axis_angle(
+            &na::Vector3::z_axis(),
+            std::f32::consts::FRAC_PI_2,
+        );
+        let channel = AnimationChannel {
+            node: 0,
+            interpolation: Interpolation::Linear,
+            inputs: vec![0.0, 1.0],
+            // The same rotation with the opposite sign is more than 180 degrees away
+            outputs: ChannelOutputs::Rotations(vec![
+                *na::UnitQuaternion::identity().quaternion(),
+                -*quarter_turn.quaternion(),
+            ]),
+        };
+
+        let rotation = match channel.sample(0.5) {
+            ChannelValue::Rotation(rotation) => rotation,
+            _ => panic!("Expected a rotation"),
+        };
+        let eighth_turn = na::UnitQuaternion::from_axis_angle(
+            &na::Vector3::z_axis(),
+            std::f32::consts::FRAC_PI_4,
+        );
+        assert!(rotation.angle_to(&eighth_turn) < 1.0e-5);
+    }
+
+    #[test]
+    fn morph_weights_are_grouped_by_output() {
+        let channel = AnimationChannel {
+            node: 0,
+            interpolation: Interpolation::Linear,
+            inputs: vec![0.0, 1.0, 2.0],
+            outputs: ChannelOutputs::MorphTargetWeights(vec![0.0, 10.0, 1.0, 20.0, 2.0, 30.0]),
+        };
+
+        let weights = sample_weights(&channel, 1.5);
+        assert_eq!(weights.len(), 2);
+        assert_near(weights[0], 1.5);
+        assert_near(weights[1], 25.0);
+    }
+
+    #[test]
+    fn cubic_spline_morph_weights_skip_tangents() {
+        let channel = AnimationChannel {
+            node: 0,
+            interpolation: Interpolation::CubicSpline,
+            inputs: vec![0.0, 1.0],
+            outputs: ChannelOutputs::MorphTargetWeights(vec![
+                9.0, 9.0, 0.0, 10.0, 0.0, 0.0, //
+                0.0, 0.0, 1.0, 20.0, 9.0, 9.0,
+            ]),
+        };
+
+        let weights = sample_weights(&channel, 0.0);
+        assert_eq!(weights.len(), 2);
+        assert_near(weights[0], 0.0);
+        assert_near(weights[1], 10.0);
+
+        let weights = sample_weights(&channel, 1.0);
+        assert_near(weights[0], 1.0);
+        assert_near(weights[1], 20.0);
+    }
+}
@@ -0,0 +1,439 @@
+use bytemuck::Zeroable;
+use serde::{Deserialize, Serialize};
+use wgpu::util::DeviceExt;
+
+use super::{
+    camera::{Camera, Lighting},
+    texture::create_morph_delta_texture,
+};
+
+// Bind groups are always bound at the same group index, see include/common.wgsl
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub enum BindGroupLayoutKey {
+    World,
+    Model,
+    Material,
+    Skin,
+}
+
+impl BindGroupLayoutKey {
+    pub fn group_index(self) -> u32 {
+        match self {
+            BindGroupLayoutKey::World => 0,
+            BindGroupLayoutKey::Model => 1,
+            BindGroupLayoutKey::Material => 2,
+            BindGroupLayoutKey::Skin => 3,
+        }
+    }
+}
+
+// Matches WorldParams in common.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct WorldParams {
+    view_proj: [[f32; 4]; 4],
+    camera_position: [f32; 4],
+    light_direction: [f32; 4],
+    // rgb color, a intensity
+    light_color: [f32; 4],
+    ambient_color: [f32; 4],
+}
+
+impl WorldParams {
+    fn new(camera: &Camera, lighting: &Lighting, aspect_ratio: f32) -> Self {
+        Self {
+            view_proj: camera.view_projection(aspect_ratio).into(),
+            camera_position: camera.eye.to_homogeneous().into(),
+            light_direction: lighting.light_direction.to_homogeneous().into(),
+            light_color: [
+                lighting.light_color[0],
+                lighting.light_color[1],
+                lighting.light_color[2],
+                lighting.light_intensity,
+            ],
+            ambient_color: [
+                lighting.ambient_color[0],
+                lighting.ambient_color[1],
+                lighting.ambient_color[2],
+                1.0,
+            ],
+        }
+    }
+}
+
+// Matches MAX_MORPH_TARGETS in include/common.wgsl
+pub const MAX_MORPH_TARGETS: usize = 64;
+
+// Where the morph target deltas of a mesh are in its model's morph delta texture, see
+// include/morphing.wgsl
+#[derive(Copy, Clone, Default, Debug)]
+pub struct MorphTargets {
+    pub target_count: u32,
+    // Vertex index of the mesh's first vertex in the model's vertex buffer
+    pub first_vertex: u32,
+    pub first_delta: u32,
+}
+
+// Matches ModelParams in common.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ModelParams {
+    world: [[f32; 4]; 4],
+    normal_matrix: [[f32; 4]; 4],
+    // Packed 4 to a vec4, uniform array elements are 16 byte aligned
+    morph_weights: [[f32; 4]; MAX_MORPH_TARGETS / 4],
+    morph_target_count: u32,
+    morph_first_vertex: u32,
+    morph_first_delta: u32,
+    _padding: u32,
+}
+
+impl ModelParams {
+    fn new(
+        world_transform: &na::Matrix4<f32>,
+        morph_targets: &MorphTargets,
+        morph_weights: &[f32],
+    ) -> Self {
+        let normal_matrix = world_transform
+            .try_inverse()
+            .unwrap_or(na::Matrix4::identity())
+            .transpose();
+
+        let mut packed_morph_weights = [[0.0; 4]; MAX_MORPH_TARGETS / 4];
+        for (index, weight) in morph_weights.iter().take(MAX_MORPH_TARGETS).enumerate() {
+            packed_morph_weights[index / 4][index % 4] = *weight;
+        }
+
+        Self {
+            world: (*world_transform).into(),
+            normal_matrix: normal_matrix.into(),
+            morph_weights: packed_morph_weights,
+            morph_target_count: morph_targets.target_count,
+            morph_first_vertex: morph_targets.first_vertex,
+            morph_first_delta: morph_targets.first_delta,
+            _padding: 0,
+        }
+    }
+}
+
+// Matches MAX_JOINTS in include/skinning.wgsl
+pub const MAX_JOINTS: usize = 128;
+
+// Matches SkinParams in include/skinning.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkinParams {
+    joint_matrices: [[[f32; 4]; 4]; MAX_JOINTS],
+}
+
+impl SkinParams {
+    fn new(joint_matrices: &[na::Matrix4<f32>]) -> Self {
+        let mut params = Self::zeroed();
+        for (param, joint_matrix) in params.joint_matrices.iter_mut().zip(joint_matrices) {
+            *param = (*joint_matrix).into();
+        }
+        params
+    }
+}
+
+pub struct ModelBindGroup {
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+    morph_targets: MorphTargets,
+}
+
+impl ModelBindGroup {
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
+
+pub struct SkinBindGroup {
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl SkinBindGroup {
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
+
+pub struct BindGroupCache {
+    world_bind_group_layout: wgpu::BindGroupLayout,
+    model_bind_group_layout: wgpu::BindGroupLayout,
+    skin_bind_group_layout: wgpu::BindGroupLayout,
+    // Fills the gaps in pipeline layouts that skip a group index
+    empty_bind_group_layout: wgpu::BindGroupLayout,
+
+    world_params_buffer: wgpu::Buffer,
+    world_bind_group: wgpu::BindGroup,
+    // Bound for meshes without a skin, which the skinned shaders draw with their model transform
+    default_skin_bind_group: SkinBindGroup,
+    // Bound for meshes without morph targets
+    empty_morph_delta_texture_view: wgpu::TextureView,
+}
+
+impl BindGroupCache {
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let world_bind_group_layout =
+            create_uniform_bind_group_layout::<WorldParams>(device, "World bind group layout");
+        let model_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Model bind group layout"),
+                entries: &[
+                    uniform_bind_group_layout_entry::<ModelParams>(0),
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: wgpu::ShaderStages::VERTEX,
+                        ty: wgpu::BindingType::Texture {
+                            sample_type: wgpu::TextureSampleType::Float { filterable: false },
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            multisampled: false,
+                        },
+                        count: None,
+                    },
+                ],
+            });
+        let skin_bind_group_layout =
+            create_uniform_bind_group_layout::<SkinParams>(device, "Skin bind group layout");
+
+        let empty_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("Empty bind group layout"),
+                entries: &[],
+            });
+
+        let world_params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("World params"),
+            contents: bytemuck::bytes_of(&WorldParams::new(
+                &Camera::default(),
+                &Lighting::default(),
+                1.0,
+            )),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let world_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some("World bind group"),
+            layout: &world_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: world_params_buffer.as_entire_binding(),
+            }],
+        });
+
+        let default_skin_bind_group =
+            create_skin_bind_group(device, &skin_bind_group_layout, "Default", &[]);
+
+        let empty_morph_delta_texture_view =
+            create_morph_delta_texture(device, queue, "Empty morph deltas", &[[0.0; 4]])
+                .create_view(&wgpu::TextureViewDescriptor::default());
+
+        Self {
+            world_bind_group_layout,
+            model_bind_group_layout,
+            skin_bind_group_layout,
+            empty_bind_group_layout,
+            world_params_buffer,
+            world_bind_group,
+            default_skin_bind_group,
+            empty_morph_delta_texture_view,
+        }
+    }
+}
+
+fn create_skin_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    label: &str,
+    joint_matrices: &[na::Matrix4<f32>],
+) -> SkinBindGroup {
+    let params_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(&format!("Skin params: {}", label)),
+        contents: bytemuck::bytes_of(&SkinParams::new(joint_matrices)),
+        usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+    });
+
+    let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some(&format!("Skin: {}", label)),
+        layout,
+        entries: &[wgpu::BindGroupEntry {
+            binding: 0,
+            resource: params_buffer.as_entire_binding(),
+        }],
+    });
+
+    SkinBindGroup {
+        params_buffer,
+        bind_group,
+    }
+}
+
+fn uniform_bind_group_layout_entry<T>(binding: u32) -> wgpu::BindGroupLayoutEntry {
+    wgpu::BindGroupLayoutEntry {
+        binding,
+        visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Buffer {
+            ty: wgpu::BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: wgpu::BufferSize::new(std::mem::size_of::<T>() as u64),
+        },
+        count: None,
+    }
+}
+
+fn create_uniform_bind_group_layout<T>(
+    device: &wgpu::Device,
+    label: &str,
+) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some(label),
+        entries: &[uniform_bind_group_layout_entry::<T>(0)],
+    })
+}
+
+impl super::State {
+    pub fn find_bind_group_layout(&self, key: BindGroupLayoutKey) -> &wgpu::BindGroupLayout {
+        match key {
+            BindGroupLayoutKey::World => &self.bindgroup_cache.world_bind_group_layout,
+            BindGroupLayoutKey::Model => &self.bindgroup_cache.model_bind_group_layout,
+            BindGroupLayoutKey::Material => self.material_cache.bind_group_layout(),
+            BindGroupLayoutKey::Skin => &self.bindgroup_cache.skin_bind_group_layout,
+        }
+    }
+
+    // Layouts indexed by group, unused group indices get an empty layout
+    pub fn find_pipeline_bind_group_layouts(
+        &self,
+        keys: &[BindGroupLayoutKey],
+    ) -> Vec<&wgpu::BindGroupLayout> {
+        let group_count = keys
+            .iter()
+            .map(|key| key.group_index() + 1)
+            .max()
+            .unwrap_or(0);
+
+        (0..group_count)
+            .map(|group_index| {
+                keys.iter()
+                    .find(|key| key.group_index() == group_index)
+                    .map_or(&self.bindgroup_cache.empty_bind_group_layout, |key| {
+                        self.find_bind_group_layout(*key)
+                    })
+            })
+            .collect()
+    }
+
+    pub fn world_bind_group(&self) -> &wgpu::BindGroup {
+        &self.bindgroup_cache.world_bind_group
+    }
+
+    pub fn update_world_params(&self, camera: &Camera, lighting: &Lighting) {
+        let aspect_ratio = self.size.width as f32 / self.size.height.max(1) as f32;
+        self.queue.write_buffer(
+            &self.bindgroup_cache.world_params_buffer,
+            0,
+            bytemuck::bytes_of(&WorldParams::new(camera, lighting, aspect_ratio)),
+        );
+    }
+
+    // Meshes with morph targets pass the texture holding their deltas
+    pub fn create_model_bind_group(
+        &self,
+        label: &str,
+        world_transform: &na::Matrix4<f32>,
+        morph_targets: MorphTargets,
+        morph_weights: &[f32],
+        morph_delta_texture_view: Option<&wgpu::TextureView>,
+    ) -> ModelBindGroup {
+        let params_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("Model params: {}", label)),
+                contents: bytemuck::bytes_of(&ModelParams::new(
+                    world_transform,
+                    &morph_targets,
+                    morph_weights,
+                )),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&format!("Model: {}", label)),
+            layout: &self.bindgroup_cache.model_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: params_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(
+                        morph_delta_texture_view
+                            .unwrap_or(&self.bindgroup_cache.empty_morph_delta_texture_view),
+                    ),
+                },
+            ],
+        });
+
+        ModelBindGroup {
+            params_buffer,
+            bind_group,
+            morph_targets,
+        }
+    }
+
+    pub fn update_model_bind_group(
+        &self,
+        model_bind_group: &ModelBindGroup,
+        world_transform: &na::Matrix4<f32>,
+        morph_weights: &[f32],
+    ) {
+        self.queue.write_buffer(
+            &model_bind_group.params_buffer,
+            0,
+            bytemuck::bytes_of(&ModelParams::new(
+                world_transform,
+                &model_bind_group.morph_targets,
+                morph_weights,
+            )),
+        );
+    }
+
+    pub fn default_skin_bind_group(&self) -> &wgpu::BindGroup {
+        self.bindgroup_cache.default_skin_bind_group.bind_group()
+    }
+
+    pub fn create_skin_bind_group(
+        &self,
+        label: &str,
+        joint_matrices: &[na::Matrix4<f32>],
+    ) -> SkinBindGroup {
+        create_skin_bind_group(
+            &self.device,
+            &self.bindgroup_cache.skin_bind_group_layout,
+            label,
+            joint_matrices,
+        )
+    }
+
+    // Joints past MAX_JOINTS are ignored
+    pub fn update_skin_bind_group(
+        &self,
+        skin_bind_group: &SkinBindGroup,
+        joint_matrices: &[na::Matrix4<f32>],
+    ) {
+        let joint_count = joint_matrices.len().min(MAX_JOINTS);
+        let joint_matrices: Vec<[[f32; 4]; 4]> = joint_matrices[..joint_count]
+            .iter()
+            .map(|joint_matrix| (*joint_matrix).into())
+            .collect();
+
+        self.queue.write_buffer(
+            &skin_bind_group.params_buffer,
+            0,
+            bytemuck::cast_slice(&joint_matrices),
+        );
+    }
+}
@@ -0,0 +1,68 @@
+// nalgebra builds OpenGL style projections with a -1..1 depth range, wgpu expects 0..1
+#[rustfmt::skip]
+const OPENGL_TO_WGPU_MATRIX: na::Matrix4<f32> = na::Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.5,
+    0.0, 0.0, 0.0, 1.0,
+);
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Camera {
+    pub eye: na::Point3<f32>,
+    pub target: na::Point3<f32>,
+    pub up: na::Vector3<f32>,
+    // Vertical field of view in radians
+    pub fov_y: f32,
+    pub z_near: f32,
+    pub z_far: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Self {
+            eye: na::Point3::new(0.0, 0.0, 5.0),
+            target: na::Point3::origin(),
+            up: na::Vector3::y(),
+            fov_y: std::f32::consts::FRAC_PI_4,
+            z_near: 0.1,
+            z_far: 1000.0,
+        }
+    }
+}
+
+impl Camera {
+    pub fn view(&self) -> na::Matrix4<f32> {
+        na::Matrix4::look_at_rh(&self.eye, &self.target, &self.up)
+    }
+
+    pub fn projection(&self, aspect_ratio: f32) -> na::Matrix4<f32> {
+        OPENGL_TO_WGPU_MATRIX
+            * na::Perspective3::new(aspect_ratio, self.fov_y, self.z_near, self.z_far)
+                .to_homogeneous()
+    }
+
+    pub fn view_projection(&self, aspect_ratio: f32) -> na::Matrix4<f32> {
+        self.projection(aspect_ratio) * self.view()
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Lighting {
+    // Direction the light travels in
+    pub light_direction: na::Vector3<f32>,
+    pub light_color: [f32; 3],
+    pub light_intensity: f32,
+    pub ambient_color: [f32; 3],
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Self {
+            light_direction: na::Vector3::new(-0.5, -1.0, -0.75).normalize(),
+            light_color: [1.0, 1.0, 1.0],
+            light_intensity: 3.0,
+            ambient_color: [0.1, 0.1, 0.1],
+        }
+    }
+}
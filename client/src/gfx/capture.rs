@@ -0,0 +1,249 @@
+use std::{
+    future::Future,
+    num::NonZeroU32,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+};
+
+use super::rendertarget::{ColorRenderTargetKey, RenderTargetKey};
+
+pub struct PendingCapture {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    padded_bytes_per_row: u32,
+    format: wgpu::TextureFormat,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CapturedImage {
+    pub width: u32,
+    pub height: u32,
+    // Tightly packed RGBA8 rows
+    pub pixels: Vec<u8>,
+}
+
+impl CapturedImage {
+    pub const BYTES_PER_PIXEL: u32 = 4;
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let offset = ((y * self.width + x) * Self::BYTES_PER_PIXEL) as usize;
+        self.pixels[offset..offset + 4].try_into().unwrap()
+    }
+
+    pub fn decode_png(png_bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut decoder = png::Decoder::new(png_bytes);
+        decoder.set_transformations(png::Transformations::EXPAND);
+
+        let mut reader = decoder.read_info()?;
+        let mut buffer = vec![0; reader.output_buffer_size()];
+        let frame_info = reader.next_frame(&mut buffer)?;
+        buffer.truncate(frame_info.buffer_size());
+
+        if frame_info.bit_depth != png::BitDepth::Eight {
+            Err(format!(
+                "Unsupported png bit depth {:?}",
+                frame_info.bit_depth
+            ))?
+        }
+
+        let pixels = match frame_info.color_type {
+            png::ColorType::Rgba => buffer,
+            png::ColorType::Rgb => buffer
+                .chunks(3)
+                .flat_map(|rgb| [rgb[0], rgb[1], rgb[2], 255])
+                .collect(),
+            color_type => Err(format!("Unsupported png color type {:?}", color_type))?,
+        };
+
+        Ok(Self {
+            width: frame_info.width,
+            height: frame_info.height,
+            pixels,
+        })
+    }
+
+    pub fn encode_png(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut png_bytes = Vec::new();
+
+        {
+            let mut encoder = png::Encoder::new(&mut png_bytes, self.width, self.height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+
+            let mut writer = encoder.write_header()?;
+            writer.write_image_data(&self.pixels)?;
+        }
+
+        Ok(png_bytes)
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    pub fn save_png(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        std::fs::write(path, self.encode_png()?)
+            .map_err(|_| format!("Failed to write file {}", path))?;
+        Ok(())
+    }
+}
+
+struct BufferMapFuture {
+    result: Arc<Mutex<(Option<Result<(), wgpu::BufferAsyncError>>, Option<Waker>)>>,
+}
+
+impl BufferMapFuture {
+    fn new(buffer_slice: wgpu::BufferSlice, mode: wgpu::MapMode) -> Self {
+        let result: Arc<Mutex<(Option<_>, Option<Waker>)>> = Arc::new(Mutex::new((None, None)));
+
+        let callback_result = result.clone();
+        buffer_slice.map_async(mode, move |map_result| {
+            let mut callback_result = callback_result.lock().unwrap();
+            callback_result.0 = Some(map_result);
+            if let Some(waker) = callback_result.1.take() {
+                waker.wake();
+            }
+        });
+
+        Self { result }
+    }
+}
+
+impl Future for BufferMapFuture {
+    type Output = Result<(), wgpu::BufferAsyncError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut result = self.result.lock().unwrap();
+        match result.0.take() {
+            Some(map_result) => Poll::Ready(map_result),
+            None => {
+                result.1 = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl super::State {
+    // Records a copy of the render target into `encoder`. The capture can be read back with
+    // `read_capture` once the encoder has been submitted. To capture `ColorRenderTargetKey::Window`
+    // this must be called from within `do_frame`, before the surface texture is presented.
+    pub fn capture_render_target(
+        &self,
+        key: ColorRenderTargetKey,
+        encoder: &mut wgpu::CommandEncoder,
+    ) -> Result<PendingCapture, Box<dyn std::error::Error>> {
+        if !self.has_render_target(RenderTargetKey::Color(key)) {
+            Err(format!("Render target {:?} doesn't exist", key))?
+        }
+
+        let format = self.find_color_render_target_format(key);
+        match format {
+            wgpu::TextureFormat::Rgba8Unorm
+            | wgpu::TextureFormat::Rgba8UnormSrgb
+            | wgpu::TextureFormat::Bgra8Unorm
+            | wgpu::TextureFormat::Bgra8UnormSrgb => {}
+            _ => Err(format!("Unsupported capture format {:?}", format))?,
+        }
+        if !self
+            .find_color_render_target_usage(key)
+            .contains(wgpu::TextureUsages::COPY_SRC)
+        {
+            Err(format!(
+                "Render target {:?} can't be captured without COPY_SRC usage",
+                key
+            ))?
+        }
+
+        let (width, height) = self.find_color_render_target_size(key);
+
+        let unpadded_bytes_per_row = width * CapturedImage::BYTES_PER_PIXEL;
+        let alignment = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row = (unpadded_bytes_per_row + alignment - 1) / alignment * alignment;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(&format!("Capture buffer: {:?}", key)),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::MAP_READ,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture: self.find_color_render_target_texture(key),
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+
+        Ok(PendingCapture {
+            buffer,
+            width,
+            height,
+            padded_bytes_per_row,
+            format,
+        })
+    }
+
+    pub async fn read_capture(
+        &self,
+        capture: PendingCapture,
+    ) -> Result<CapturedImage, Box<dyn std::error::Error>> {
+        let buffer_slice = capture.buffer.slice(..);
+        let map_future = BufferMapFuture::new(buffer_slice, wgpu::MapMode::Read);
+
+        #[cfg(not(target_family = "wasm"))]
+        {
+            self.device.poll(wgpu::Maintain::Wait);
+        }
+
+        map_future.await?;
+
+        let unpadded_bytes_per_row = (capture.width * CapturedImage::BYTES_PER_PIXEL) as usize;
+        let mut pixels = Vec::with_capacity(unpadded_bytes_per_row * capture.height as usize);
+
+        {
+            let mapped_bytes = buffer_slice.get_mapped_range();
+            for row in mapped_bytes.chunks(capture.padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row]);
+            }
+        }
+        capture.buffer.unmap();
+
+        if let wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb =
+            capture.format
+        {
+            for pixel in pixels.chunks_mut(CapturedImage::BYTES_PER_PIXEL as usize) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Ok(CapturedImage {
+            width: capture.width,
+            height: capture.height,
+            pixels,
+        })
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn save_capture_png(
+        &self,
+        capture: PendingCapture,
+        path: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.read_capture(capture).await?.save_png(path)
+    }
+}
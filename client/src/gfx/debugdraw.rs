@@ -51,9 +51,8 @@ impl RenderTest {
             },
         ));
 
-        self.model
-            .from_gltf("data/testmodels/Box.glb", state)
-            .await?;
+        self.model =
+            Model::from_gltf("data/testmodels/Box.glb", VertexShaderKey::GltfVS, state).await?;
 
         Ok(())
     }
@@ -68,7 +67,7 @@ impl RenderTest {
         //render_pass.set_vertex_buffer(0, self.model.vertex_buffer.as_ref().unwrap().slice(..));
         render_pass.set_index_buffer(
             self.model.index_buffer.as_ref().unwrap().slice(..),
-            wgpu::IndexFormat::Uint32,
+            self.model.index_format.index_format(),
         );
         render_pass.draw(0..3, 0..1);
     }
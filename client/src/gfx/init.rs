@@ -2,7 +2,12 @@ use std::sync::{Arc, RwLock};
 
 use winit::{dpi::PhysicalSize, window::WindowBuilder};
 
-use super::{rendertarget::RenderTargetCache, shader::ShaderCache};
+use super::{
+    bindgroup::BindGroupCache, material::MaterialCache, pipeline::PipelineCache,
+    rendertarget::RenderTargetCache, shader::ShaderCache,
+};
+
+pub const HEADLESS_COLOR_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
 
 pub async fn init() -> Result<(super::State, super::super::WindowState), Box<dyn std::error::Error>>
 {
@@ -38,6 +43,85 @@ pub async fn init() -> Result<(super::State, super::super::WindowState), Box<dyn
         .await
         .ok_or("Graphics adapter not found")?;
 
+    let (device, queue) = request_device(&adapter).await?;
+
+    let supported_formats = surface.get_supported_formats(&adapter);
+    let format = supported_formats
+        .first()
+        .ok_or("Window surface format not compatible with graphics adapter")?;
+
+    // Frame captures copy out of the surface texture, which WebGL can't do
+    let usage = if cfg!(target_family = "wasm") {
+        wgpu::TextureUsages::RENDER_ATTACHMENT
+    } else {
+        wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC
+    };
+
+    let surface_config = wgpu::SurfaceConfiguration {
+        usage: usage,
+        format: *format,
+        width: size.width,
+        height: size.height,
+        present_mode: wgpu::PresentMode::Fifo,
+    };
+    surface.configure(&device, &surface_config);
+
+    let state = init_state(Some(surface), device, queue, surface_config, size).await?;
+
+    Ok((
+        state,
+        super::super::WindowState {
+            window: Some(window),
+            event_loop: Some(event_loop),
+        },
+    ))
+}
+
+// Renders `ColorRenderTargetKey::Window` into an offscreen texture instead of a window surface.
+// The fallback (software) adapter is preferred so this also works on machines without a GPU.
+pub async fn init_headless(
+    size: PhysicalSize<u32>,
+) -> Result<super::State, Box<dyn std::error::Error>> {
+    let instance = wgpu::Instance::new(wgpu::Backends::all());
+
+    let mut adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::default(),
+            compatible_surface: None,
+            force_fallback_adapter: true,
+        })
+        .await;
+
+    if adapter.is_none() {
+        adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                compatible_surface: None,
+                force_fallback_adapter: false,
+            })
+            .await;
+    }
+
+    let adapter = adapter.ok_or("Graphics adapter not found")?;
+
+    let (device, queue) = request_device(&adapter).await?;
+
+    let surface_config = wgpu::SurfaceConfiguration {
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::COPY_SRC
+            | wgpu::TextureUsages::TEXTURE_BINDING,
+        format: HEADLESS_COLOR_FORMAT,
+        width: size.width,
+        height: size.height,
+        present_mode: wgpu::PresentMode::Fifo,
+    };
+
+    init_state(None, device, queue, surface_config, size).await
+}
+
+async fn request_device(
+    adapter: &wgpu::Adapter,
+) -> Result<(wgpu::Device, wgpu::Queue), Box<dyn std::error::Error>> {
     let limits = if cfg!(target_family = "wasm") {
         wgpu::Limits::downlevel_webgl2_defaults().using_resolution(adapter.limits())
     } else {
@@ -47,7 +131,9 @@ pub async fn init() -> Result<(super::State, super::super::WindowState), Box<dyn
     let (device, queue) = adapter
         .request_device(
             &wgpu::DeviceDescriptor {
-                features: wgpu::Features::empty(),
+                // Debug drawing uses these where they're available
+                features: adapter.features()
+                    & (wgpu::Features::POLYGON_MODE_LINE | wgpu::Features::POLYGON_MODE_POINT),
                 limits: limits,
                 label: None,
             },
@@ -55,19 +141,18 @@ pub async fn init() -> Result<(super::State, super::super::WindowState), Box<dyn
         )
         .await?;
 
-    let supported_formats = surface.get_supported_formats(&adapter);
-    let format = supported_formats
-        .first()
-        .ok_or("Window surface format not compatible with graphics adapter")?;
+    Ok((device, queue))
+}
 
-    let surface_config = wgpu::SurfaceConfiguration {
-        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-        format: *format,
-        width: size.width,
-        height: size.height,
-        present_mode: wgpu::PresentMode::Fifo,
-    };
-    surface.configure(&device, &surface_config);
+async fn init_state(
+    surface: Option<wgpu::Surface>,
+    device: wgpu::Device,
+    queue: wgpu::Queue,
+    surface_config: wgpu::SurfaceConfiguration,
+    size: PhysicalSize<u32>,
+) -> Result<super::State, Box<dyn std::error::Error>> {
+    let bindgroup_cache = BindGroupCache::new(&device, &queue);
+    let material_cache = MaterialCache::new(&device, &queue);
 
     let device_arc = Arc::new(device);
     let mut state = super::State {
@@ -78,17 +163,14 @@ pub async fn init() -> Result<(super::State, super::super::WindowState), Box<dyn
         surface_config: surface_config,
         size: size,
         shader_cache: RwLock::new(ShaderCache::new(device_arc.clone())),
+        pipeline_cache: RwLock::new(PipelineCache::default()),
         rendertarget_cache: RenderTargetCache::default(),
+        bindgroup_cache: bindgroup_cache,
+        material_cache: material_cache,
     };
 
     state.init_shader_cache().await?;
     state.init_builtin_render_targets()?;
 
-    Ok((
-        state,
-        super::super::WindowState {
-            window: Some(window),
-            event_loop: Some(event_loop),
-        },
-    ))
+    Ok(state)
 }
@@ -0,0 +1,331 @@
+use wgpu::util::DeviceExt;
+
+use super::{
+    pipelinestate::{BlendMode, CullMode},
+    texture::create_rgba8_texture,
+};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AlphaMode {
+    Opaque,
+    Mask { cutoff: f32 },
+    Blend,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MaterialTexture {
+    // Indices into the owning model's textures and samplers
+    pub texture: usize,
+    pub sampler: Option<usize>,
+    // TEXCOORD_0 or TEXCOORD_1
+    pub tex_coord: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaterialDescriptor {
+    pub name: String,
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: Option<MaterialTexture>,
+    pub metallic_factor: f32,
+    pub roughness_factor: f32,
+    pub metallic_roughness_texture: Option<MaterialTexture>,
+    pub normal_scale: f32,
+    pub normal_texture: Option<MaterialTexture>,
+    pub occlusion_strength: f32,
+    pub occlusion_texture: Option<MaterialTexture>,
+    pub emissive_factor: [f32; 3],
+    pub emissive_texture: Option<MaterialTexture>,
+    pub alpha_mode: AlphaMode,
+    pub double_sided: bool,
+}
+
+impl Default for MaterialDescriptor {
+    fn default() -> Self {
+        Self {
+            name: "Default material".to_string(),
+            base_color_factor: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic_factor: 1.0,
+            roughness_factor: 1.0,
+            metallic_roughness_texture: None,
+            normal_scale: 1.0,
+            normal_texture: None,
+            occlusion_strength: 1.0,
+            occlusion_texture: None,
+            emissive_factor: [0.0, 0.0, 0.0],
+            emissive_texture: None,
+            alpha_mode: AlphaMode::Opaque,
+            double_sided: false,
+        }
+    }
+}
+
+impl MaterialDescriptor {
+    // Pipeline state the material needs, None leaves the render pass's as it is. pbr.wgsl outputs
+    // straight alpha.
+    pub fn blend_mode(&self) -> Option<BlendMode> {
+        match self.alpha_mode {
+            AlphaMode::Blend => Some(BlendMode::Alpha),
+            AlphaMode::Opaque | AlphaMode::Mask { .. } => None,
+        }
+    }
+
+    pub fn cull_mode(&self) -> Option<CullMode> {
+        self.double_sided.then_some(CullMode::None)
+    }
+
+    // Blended surfaces don't hide what's drawn behind them later
+    pub fn depth_write_enabled(&self) -> Option<bool> {
+        self.is_blended().then_some(false)
+    }
+
+    pub fn is_blended(&self) -> bool {
+        matches!(self.alpha_mode, AlphaMode::Blend)
+    }
+}
+
+// Matches MaterialParams in pbr.wgsl
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct MaterialParams {
+    base_color_factor: [f32; 4],
+    emissive_factor: [f32; 3],
+    metallic_factor: f32,
+    roughness_factor: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+    alpha_cutoff: f32,
+    alpha_mode: u32,
+    // In the order of the texture slots
+    tex_coords: [u32; 5],
+    _padding: [u32; 2],
+}
+
+impl MaterialParams {
+    const ALPHA_MODE_OPAQUE: u32 = 0;
+    const ALPHA_MODE_MASK: u32 = 1;
+    const ALPHA_MODE_BLEND: u32 = 2;
+
+    fn new(descriptor: &MaterialDescriptor) -> Self {
+        let (alpha_mode, alpha_cutoff) = match descriptor.alpha_mode {
+            AlphaMode::Opaque => (Self::ALPHA_MODE_OPAQUE, 0.0),
+            AlphaMode::Mask { cutoff } => (Self::ALPHA_MODE_MASK, cutoff),
+            AlphaMode::Blend => (Self::ALPHA_MODE_BLEND, 0.0),
+        };
+
+        Self {
+            base_color_factor: descriptor.base_color_factor,
+            emissive_factor: descriptor.emissive_factor,
+            metallic_factor: descriptor.metallic_factor,
+            roughness_factor: descriptor.roughness_factor,
+            normal_scale: descriptor.normal_scale,
+            occlusion_strength: descriptor.occlusion_strength,
+            alpha_cutoff,
+            alpha_mode,
+            tex_coords: [
+                descriptor.base_color_texture,
+                descriptor.metallic_roughness_texture,
+                descriptor.normal_texture,
+                descriptor.occlusion_texture,
+                descriptor.emissive_texture,
+            ]
+            .map(|texture| texture.map_or(0, |texture| texture.tex_coord)),
+            _padding: [0; 2],
+        }
+    }
+}
+
+pub struct Material {
+    pub descriptor: MaterialDescriptor,
+    params_buffer: wgpu::Buffer,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Material {
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+}
+
+pub struct MaterialCache {
+    bind_group_layout: wgpu::BindGroupLayout,
+    default_sampler: wgpu::Sampler,
+    white_texture: wgpu::Texture,
+    flat_normal_texture: wgpu::Texture,
+}
+
+impl MaterialCache {
+    // Texture slots in the order of their bindings in pbr.wgsl, each followed by its sampler
+    const TEXTURE_SLOT_COUNT: u32 = 5;
+
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue) -> Self {
+        let mut layout_entries = vec![wgpu::BindGroupLayoutEntry {
+            binding: 0,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Buffer {
+                ty: wgpu::BufferBindingType::Uniform,
+                has_dynamic_offset: false,
+                min_binding_size: wgpu::BufferSize::new(
+                    std::mem::size_of::<MaterialParams>() as u64
+                ),
+            },
+            count: None,
+        }];
+
+        for slot in 0..Self::TEXTURE_SLOT_COUNT {
+            layout_entries.push(wgpu::BindGroupLayoutEntry {
+                binding: 1 + slot * 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            });
+            layout_entries.push(wgpu::BindGroupLayoutEntry {
+                binding: 2 + slot * 2,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            });
+        }
+
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: Some("Material bind group layout"),
+            entries: &layout_entries,
+        });
+
+        let default_sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("Default material sampler"),
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        let white_texture = create_rgba8_texture(
+            device,
+            queue,
+            "Default white texture",
+            1,
+            1,
+            &[255, 255, 255, 255],
+            false,
+        );
+
+        let flat_normal_texture = create_rgba8_texture(
+            device,
+            queue,
+            "Default normal texture",
+            1,
+            1,
+            &[128, 128, 255, 255],
+            false,
+        );
+
+        Self {
+            bind_group_layout,
+            default_sampler,
+            white_texture,
+            flat_normal_texture,
+        }
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+}
+
+impl super::State {
+    pub fn create_material(
+        &self,
+        descriptor: MaterialDescriptor,
+        textures: &[wgpu::Texture],
+        samplers: &[wgpu::Sampler],
+    ) -> Material {
+        let material_cache = &self.material_cache;
+
+        let params_buffer = self
+            .device
+            .create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("Material params: {}", descriptor.name)),
+                contents: bytemuck::bytes_of(&MaterialParams::new(&descriptor)),
+                usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            });
+
+        let texture_slots = [
+            (descriptor.base_color_texture, &material_cache.white_texture),
+            (
+                descriptor.metallic_roughness_texture,
+                &material_cache.white_texture,
+            ),
+            (
+                descriptor.normal_texture,
+                &material_cache.flat_normal_texture,
+            ),
+            (descriptor.occlusion_texture, &material_cache.white_texture),
+            (descriptor.emissive_texture, &material_cache.white_texture),
+        ];
+
+        let texture_views: Vec<wgpu::TextureView> = texture_slots
+            .iter()
+            .map(|(material_texture, default_texture)| {
+                material_texture
+                    .map_or(*default_texture, |material_texture| {
+                        &textures[material_texture.texture]
+                    })
+                    .create_view(&wgpu::TextureViewDescriptor::default())
+            })
+            .collect();
+
+        let mut entries = vec![wgpu::BindGroupEntry {
+            binding: 0,
+            resource: params_buffer.as_entire_binding(),
+        }];
+
+        for (slot, ((material_texture, _), texture_view)) in
+            texture_slots.iter().zip(texture_views.iter()).enumerate()
+        {
+            let sampler = material_texture
+                .and_then(|material_texture| material_texture.sampler)
+                .map_or(&material_cache.default_sampler, |sampler| {
+                    &samplers[sampler]
+                });
+
+            entries.push(wgpu::BindGroupEntry {
+                binding: 1 + slot as u32 * 2,
+                resource: wgpu::BindingResource::TextureView(texture_view),
+            });
+            entries.push(wgpu::BindGroupEntry {
+                binding: 2 + slot as u32 * 2,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            });
+        }
+
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: Some(&format!("Material: {}", descriptor.name)),
+            layout: &material_cache.bind_group_layout,
+            entries: &entries,
+        });
+
+        Material {
+            descriptor,
+            params_buffer,
+            bind_group,
+        }
+    }
+
+    // Only updates the material factors, texture changes need a new material
+    pub fn update_material(&self, material: &mut Material, descriptor: MaterialDescriptor) {
+        self.queue.write_buffer(
+            &material.params_buffer,
+            0,
+            bytemuck::bytes_of(&MaterialParams::new(&descriptor)),
+        );
+        material.descriptor = descriptor;
+    }
+}
@@ -0,0 +1,493 @@
+use std::{collections::HashMap, hash::Hash};
+
+use super::pipelinestate::PrimitiveTopology;
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum NormalGeneration {
+    // Every triangle gets its own vertices, the glTF spec asks for these
+    #[default]
+    Flat,
+    // Vertices at the same position share an area weighted normal
+    Smooth,
+}
+
+// How imported primitives are processed before they're uploaded
+#[derive(Clone, Debug)]
+pub struct MeshProcessingOptions {
+    // Used for triangle primitives without normals
+    pub normals: NormalGeneration,
+    // Triangle primitives with texture coordinates but no tangents get generated tangents
+    pub generate_tangents: bool,
+    // Merges vertices that are identical after generation
+    pub weld_vertices: bool,
+    // Reorders the triangles of triangle lists for the post-transform vertex cache
+    pub optimize_indices: bool,
+}
+
+impl Default for MeshProcessingOptions {
+    fn default() -> Self {
+        Self {
+            normals: NormalGeneration::Flat,
+            generate_tangents: true,
+            weld_vertices: false,
+            optimize_indices: true,
+        }
+    }
+}
+
+// Triangles of a primitive, with strips unrolled. Other topologies have none.
+pub fn triangles(topology: PrimitiveTopology, indices: &[u32]) -> Vec<[u32; 3]> {
+    match topology {
+        PrimitiveTopology::TriangleList => indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .collect(),
+        // Every other triangle of a strip is flipped to keep the winding
+        PrimitiveTopology::TriangleStrip => indices
+            .windows(3)
+            .enumerate()
+            .map(|(i, triangle)| match i % 2 {
+                0 => [triangle[0], triangle[1], triangle[2]],
+                _ => [triangle[1], triangle[0], triangle[2]],
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn triangle_normal(positions: &[na::Vector3<f32>], [a, b, c]: [u32; 3]) -> na::Vector3<f32> {
+    let (a, b, c) = (
+        positions[a as usize],
+        positions[b as usize],
+        positions[c as usize],
+    );
+    (b - a).cross(&(c - a))
+}
+
+fn normalize_or_z(vector: na::Vector3<f32>) -> na::Vector3<f32> {
+    vector.try_normalize(1.0e-12).unwrap_or_else(na::Vector3::z)
+}
+
+// Expects vertices that aren't shared between triangles, see `split_vertices`
+pub fn flat_normals(
+    positions: &[na::Vector3<f32>],
+    triangles: &[[u32; 3]],
+) -> Vec<na::Vector3<f32>> {
+    let mut normals = vec![na::Vector3::z(); positions.len()];
+    for triangle in triangles {
+        let normal = normalize_or_z(triangle_normal(positions, *triangle));
+        for vertex in triangle {
+            normals[*vertex as usize] = normal;
+        }
+    }
+    normals
+}
+
+// Vertices are smoothed by position rather than index, so seams where the texture coordinates
+// split a vertex don't show up in the lighting
+pub fn smooth_normals(
+    positions: &[na::Vector3<f32>],
+    triangles: &[[u32; 3]],
+) -> Vec<na::Vector3<f32>> {
+    let position_key = |position: &na::Vector3<f32>| position.map(f32::to_bits);
+
+    // The cross product's length is twice the triangle's area, which weights the sum
+    let mut position_normals: HashMap<na::Vector3<u32>, na::Vector3<f32>> = HashMap::new();
+    for triangle in triangles {
+        let normal = triangle_normal(positions, *triangle);
+        for vertex in triangle {
+            *position_normals
+                .entry(position_key(&positions[*vertex as usize]))
+                .or_insert(na::Vector3::zeros()) += normal;
+        }
+    }
+
+    positions
+        .iter()
+        .map(|position| {
+            normalize_or_z(
+                position_normals
+                    .get(&position_key(position))
+                    .copied()
+                    .unwrap_or(na::Vector3::zeros()),
+            )
+        })
+        .collect()
+}
+
+// Tangents along increasing u, weighted by the angle at each triangle corner like MikkTSpace. The
+// w component is the handedness, bitangent = cross(normal, tangent) * w as in glTF.
+pub fn tangents(
+    positions: &[na::Vector3<f32>],
+    normals: &[na::Vector3<f32>],
+    tex_coords: &[na::Vector2<f32>],
+    triangles: &[[u32; 3]],
+) -> Vec<na::Vector4<f32>> {
+    let mut tangents = vec![na::Vector3::zeros(); positions.len()];
+    let mut bitangents = vec![na::Vector3::zeros(); positions.len()];
+
+    for triangle in triangles {
+        let [a, b, c] = triangle.map(|vertex| vertex as usize);
+        let edge_1 = positions[b] - positions[a];
+        let edge_2 = positions[c] - positions[a];
+        let tex_edge_1 = tex_coords[b] - tex_coords[a];
+        let tex_edge_2 = tex_coords[c] - tex_coords[a];
+
+        let determinant = tex_edge_1.x * tex_edge_2.y - tex_edge_2.x * tex_edge_1.y;
+        if determinant.abs() < 1.0e-12 {
+            continue;
+        }
+        let tangent = (edge_1 * tex_edge_2.y - edge_2 * tex_edge_1.y) / determinant;
+        let bitangent = (edge_2 * tex_edge_1.x - edge_1 * tex_edge_2.x) / determinant;
+
+        for (corner, (from, to)) in [(a, (b, c)), (b, (c, a)), (c, (a, b))] {
+            let angle =
+                (positions[from] - positions[corner]).angle(&(positions[to] - positions[corner]));
+            if angle.is_finite() {
+                tangents[corner] += tangent * angle;
+                bitangents[corner] += bitangent * angle;
+            }
+        }
+    }
+
+    normals
+        .iter()
+        .zip(tangents.iter().zip(&bitangents))
+        .map(|(normal, (tangent, bitangent))| {
+            // Gram-Schmidt, vertices without a usable tangent get any perpendicular one
+            let tangent = (tangent - normal * normal.dot(tangent))
+                .try_normalize(1.0e-12)
+                .unwrap_or_else(|| {
+                    let axis = match normal.x.abs() < 0.9 {
+                        true => na::Vector3::x(),
+                        false => na::Vector3::y(),
+                    };
+                    normalize_or_z(axis - normal * normal.dot(&axis))
+                });
+            let handedness = match normal.cross(&tangent).dot(bitangent) < 0.0 {
+                true => -1.0,
+                false => 1.0,
+            };
+            tangent.push(handedness)
+        })
+        .collect()
+}
+
+// Gives every triangle corner its own vertex. Returns the original vertex of each new vertex,
+// the new triangles are the consecutive vertices.
+pub fn split_vertices(triangles: &[[u32; 3]]) -> Vec<u32> {
+    triangles.iter().flatten().copied().collect()
+}
+
+// Merges vertices with equal keys. Returns the original vertex of each unique vertex, and the
+// unique vertex each original vertex was merged into.
+pub fn weld_vertices<K, I>(vertex_keys: I) -> (Vec<u32>, Vec<u32>)
+where
+    K: Hash + Eq,
+    I: Iterator<Item = K>,
+{
+    let mut unique_vertices: HashMap<K, u32> = HashMap::new();
+    let mut original_vertices = Vec::new();
+
+    let welded_vertices = vertex_keys
+        .enumerate()
+        .map(|(vertex, key)| {
+            *unique_vertices.entry(key).or_insert_with(|| {
+                original_vertices.push(vertex as u32);
+                original_vertices.len() as u32 - 1
+            })
+        })
+        .collect();
+
+    (original_vertices, welded_vertices)
+}
+
+const VERTEX_CACHE_SIZE: usize = 32;
+
+// From Tom Forsyth's "Linear-Speed Vertex Cache Optimisation"
+fn vertex_cache_score(cache_position: Option<usize>, remaining_triangles: usize) -> f32 {
+    if remaining_triangles == 0 {
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        None => 0.0,
+        // The last triangle's vertices are scored lower, so its neighbours don't all go next
+        Some(position) if position < 3 => 0.75,
+        Some(position) => (1.0 - (position - 3) as f32 / (VERTEX_CACHE_SIZE - 3) as f32)
+            .max(0.0)
+            .powf(1.5),
+    };
+
+    // Vertices with few triangles left are finished off first
+    let valence_boost = 2.0 * (remaining_triangles as f32).powf(-0.5);
+
+    cache_score + valence_boost
+}
+
+// Reorders the triangles of a triangle list so vertices are reused while they're still in the
+// post-transform cache. Indices out of range are left in their order.
+pub fn optimize_vertex_cache(indices: &[u32], vertex_count: usize) -> Vec<u32> {
+    let triangle_count = indices.len() / 3;
+    if triangle_count == 0 || indices.iter().any(|index| *index as usize >= vertex_count) {
+        return indices.to_vec();
+    }
+
+    let mut vertex_triangles: Vec<Vec<usize>> = vec![Vec::new(); vertex_count];
+    for (triangle, vertices) in indices.chunks_exact(3).enumerate() {
+        for vertex in vertices {
+            vertex_triangles[*vertex as usize].push(triangle);
+        }
+    }
+
+    let mut remaining_triangles: Vec<usize> = vertex_triangles.iter().map(Vec::len).collect();
+    let mut vertex_scores: Vec<f32> = remaining_triangles
+        .iter()
+        .map(|remaining| vertex_cache_score(None, *remaining))
+        .collect();
+    let triangle_score = |vertex_scores: &[f32], triangle: usize| -> f32 {
+        indices[triangle * 3..triangle * 3 + 3]
+            .iter()
+            .map(|vertex| vertex_scores[*vertex as usize])
+            .sum()
+    };
+
+    let mut triangle_added = vec![false; triangle_count];
+    let mut cache: Vec<u32> = Vec::with_capacity(VERTEX_CACHE_SIZE + 3);
+    let mut optimized_indices = Vec::with_capacity(triangle_count * 3);
+    let mut best_triangle = None;
+    let mut next_unadded_triangle = 0;
+
+    for _ in 0..triangle_count {
+        // Continue with the next triangle in the original order when nothing in the cache has
+        // triangles left. Searching all of them for the best score would be quadratic for meshes
+        // of disconnected triangles.
+        let triangle = best_triangle.unwrap_or_else(|| {
+            while triangle_added[next_unadded_triangle] {
+                next_unadded_triangle += 1;
+            }
+            next_unadded_triangle
+        });
+
+        triangle_added[triangle] = true;
+        let vertices = &indices[triangle * 3..triangle * 3 + 3];
+        optimized_indices.extend_from_slice(vertices);
+
+        for vertex in vertices {
+            remaining_triangles[*vertex as usize] -= 1;
+            cache.retain(|cached| cached != vertex);
+        }
+        let previous_cache = std::mem::replace(&mut cache, vertices.to_vec());
+        cache.extend(previous_cache);
+
+        // Vertices pushed out of the cache are rescored once more, without a cache position
+        let evicted_vertices = cache.split_off(cache.len().min(VERTEX_CACHE_SIZE));
+        for vertex in &evicted_vertices {
+            vertex_scores[*vertex as usize] =
+                vertex_cache_score(None, remaining_triangles[*vertex as usize]);
+        }
+        for (position, vertex) in cache.iter().enumerate() {
+            vertex_scores[*vertex as usize] =
+                vertex_cache_score(Some(position), remaining_triangles[*vertex as usize]);
+        }
+
+        best_triangle = cache
+            .iter()
+            .flat_map(|vertex| &vertex_triangles[*vertex as usize])
+            .filter(|triangle| !triangle_added[**triangle])
+            .max_by(|a, b| {
+                triangle_score(&vertex_scores, **a).total_cmp(&triangle_score(&vertex_scores, **b))
+            })
+            .copied();
+    }
+
+    optimized_indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quad_positions() -> Vec<na::Vector3<f32>> {
+        vec![
+            na::Vector3::new(0.0, 0.0, 0.0),
+            na::Vector3::new(1.0, 0.0, 0.0),
+            na::Vector3::new(1.0, 1.0, 0.0),
+            na::Vector3::new(0.0, 1.0, 0.0),
+        ]
+    }
+
+    const QUAD_TRIANGLES: [[u32; 3]; 2] = [[0, 1, 2], [0, 2, 3]];
+
+    fn sorted_triangles(indices: &[u32]) -> Vec<[u32; 3]> {
+        let mut triangles = triangles(PrimitiveTopology::TriangleList, indices);
+        triangles.sort();
+        triangles
+    }
+
+    fn grid_indices(width: u32, height: u32) -> Vec<u32> {
+        (0..height)
+            .flat_map(|y| (0..width).map(move |x| y * (width + 1) + x))
+            .flat_map(|corner| {
+                let above = corner + width + 1;
+                [corner, corner + 1, above + 1, corner, above + 1, above]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn triangle_strip_flips_odd_triangles() {
+        let positions = [(0.0, 0.0), (0.0, 1.0), (1.0, 0.0), (1.0, 1.0), (2.0, 0.0)]
+            .map(|(x, y)| na::Vector3::new(x, y, 0.0));
+        let strip = triangles(PrimitiveTopology::TriangleStrip, &[0, 1, 2, 3, 4]);
+
+        assert_eq!(strip, vec![[0, 1, 2], [2, 1, 3], [2, 3, 4]]);
+        for triangle in strip {
+            assert!(triangle_normal(&positions, triangle).z < 0.0);
+        }
+    }
+
+    #[test]
+    fn other_topologies_have_no_triangles() {
+        assert!(triangles(PrimitiveTopology::LineList, &[0, 1, 2, 3]).is_empty());
+        assert!(triangles(PrimitiveTopology::PointList, &[0, 1, 2]).is_empty());
+    }
+
+    #[test]
+    fn split_vertices_gives_every_corner_a_vertex() {
+        assert_eq!(split_vertices(&QUAD_TRIANGLES), vec![0, 1, 2, 0, 2, 3]);
+    }
+
+    #[test]
+    fn flat_normals_face_out_of_each_triangle() {
+        let quad_positions = quad_positions();
+        let mut positions: Vec<_> = split_vertices(&QUAD_TRIANGLES)
+            .iter()
+            .map(|vertex| quad_positions[*vertex as usize])
+            .collect();
+        // The second triangle is folded up along the x axis
+        positions[4] = na::Vector3::new(1.0, 0.0, 1.0);
+        positions[5] = na::Vector3::new(0.0, 0.0, 1.0);
+
+        let normals = flat_normals(&positions, &[[0, 1, 2], [3, 4, 5]]);
+        for normal in &normals[0..3] {
+            assert_eq!(*normal, na::Vector3::z());
+        }
+        for normal in &normals[3..6] {
+            assert_eq!(*normal, -na::Vector3::y());
+        }
+    }
+
+    #[test]
+    fn flat_normals_of_degenerate_triangles_are_z() {
+        let positions = vec![na::Vector3::new(1.0, 2.0, 3.0); 3];
+        assert_eq!(
+            flat_normals(&positions, &[[0, 1, 2]]),
+            vec![na::Vector3::z(); 3]
+        );
+    }
+
+    #[test]
+    fn smooth_normals_are_shared_by_position() {
+        // Two triangles meeting at a right angle along the x axis, without sharing vertices
+        let positions = vec![
+            na::Vector3::new(0.0, 0.0, 0.0),
+            na::Vector3::new(1.0, 0.0, 0.0),
+            na::Vector3::new(0.0, 1.0, 0.0),
+            na::Vector3::new(1.0, 0.0, 0.0),
+            na::Vector3::new(0.0, 0.0, 0.0),
+            na::Vector3::new(0.0, 0.0, 1.0),
+        ];
+        let normals = smooth_normals(&positions, &[[0, 1, 2], [4, 3, 5]]);
+
+        let edge_normal = na::Vector3::new(0.0, -1.0, 1.0).normalize();
+        for vertex in [0, 1, 3, 4] {
+            assert!((normals[vertex] - edge_normal).norm() < 1.0e-6);
+        }
+        assert_eq!(normals[2], na::Vector3::z());
+        assert_eq!(normals[5], -na::Vector3::y());
+    }
+
+    #[test]
+    fn tangents_follow_u_with_handedness() {
+        let positions = quad_positions();
+        let normals = vec![na::Vector3::z(); 4];
+        let tex_coords: Vec<_> = positions
+            .iter()
+            .map(|position| na::Vector2::new(position.x, position.y))
+            .collect();
+
+        for tangent in tangents(&positions, &normals, &tex_coords, &QUAD_TRIANGLES) {
+            assert!((tangent - na::Vector4::new(1.0, 0.0, 0.0, 1.0)).norm() < 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn tangents_of_mirrored_tex_coords_are_left_handed() {
+        let positions = quad_positions();
+        let normals = vec![na::Vector3::z(); 4];
+        let tex_coords: Vec<_> = positions
+            .iter()
+            .map(|position| na::Vector2::new(1.0 - position.x, position.y))
+            .collect();
+
+        for tangent in tangents(&positions, &normals, &tex_coords, &QUAD_TRIANGLES) {
+            assert!((tangent - na::Vector4::new(-1.0, 0.0, 0.0, -1.0)).norm() < 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn tangents_without_tex_coord_gradient_are_perpendicular() {
+        let positions = quad_positions();
+        let normals = vec![na::Vector3::z(); 4];
+        let tex_coords = vec![na::Vector2::zeros(); 4];
+
+        for tangent in tangents(&positions, &normals, &tex_coords, &QUAD_TRIANGLES) {
+            assert!(tangent.xyz().dot(&na::Vector3::z()).abs() < 1.0e-6);
+            assert!((tangent.xyz().norm() - 1.0).abs() < 1.0e-6);
+        }
+    }
+
+    #[test]
+    fn weld_vertices_round_trips() {
+        let keys = [3, 1, 3, 2, 1, 3];
+        let (original_vertices, welded_vertices) = weld_vertices(keys.iter());
+
+        assert_eq!(original_vertices, vec![0, 1, 3]);
+        assert_eq!(welded_vertices, vec![0, 1, 0, 2, 1, 0]);
+        for (vertex, welded_vertex) in welded_vertices.iter().enumerate() {
+            assert_eq!(
+                keys[original_vertices[*welded_vertex as usize] as usize],
+                keys[vertex]
+            );
+        }
+    }
+
+    #[test]
+    fn optimize_vertex_cache_keeps_triangles() {
+        let indices = grid_indices(16, 16);
+        let optimized_indices = optimize_vertex_cache(&indices, 17 * 17);
+
+        assert_eq!(
+            sorted_triangles(&optimized_indices),
+            sorted_triangles(&indices)
+        );
+    }
+
+    #[test]
+    fn optimize_vertex_cache_keeps_disconnected_triangles() {
+        let indices: Vec<u32> = (0..300).rev().collect();
+        let optimized_indices = optimize_vertex_cache(&indices, 300);
+
+        assert_eq!(
+            sorted_triangles(&optimized_indices),
+            sorted_triangles(&indices)
+        );
+    }
+
+    #[test]
+    fn optimize_vertex_cache_ignores_indices_out_of_range() {
+        let indices = [0, 1, 2, 2, 1, 3];
+        assert_eq!(optimize_vertex_cache(&indices, 3), indices.to_vec());
+    }
+}
@@ -1,20 +1,37 @@
+pub mod animation;
+pub mod bindgroup;
+pub mod camera;
+pub mod capture;
 pub mod debugdraw;
 pub mod init;
+pub mod material;
+pub mod meshprocessing;
 pub mod model;
+pub mod pipeline;
+pub mod pipelinestate;
 pub mod renderpass;
 pub mod rendertarget;
 pub mod shader;
 pub mod shader_attributes;
+pub mod shader_constants;
+pub mod shader_diagnostics;
+#[cfg(not(target_family = "wasm"))]
+pub mod shader_hot_reload;
+pub mod shader_preprocessor;
+pub mod texture;
 
 use std::sync::{Arc, RwLock};
 
-pub use init::init;
+pub use init::{init, init_headless};
 
+use bindgroup::BindGroupCache;
+use material::MaterialCache;
+use pipeline::PipelineCache;
 use rendertarget::RenderTargetCache;
 use shader::ShaderCache;
 
 pub struct State {
-    surface: wgpu::Surface,
+    surface: Option<wgpu::Surface>,
     current_surface_texture: Option<wgpu::SurfaceTexture>,
     device: Arc<wgpu::Device>,
     queue: wgpu::Queue,
@@ -22,7 +39,43 @@ pub struct State {
     size: winit::dpi::PhysicalSize<u32>,
 
     shader_cache: RwLock<ShaderCache>,
+    pipeline_cache: RwLock<PipelineCache>,
     rendertarget_cache: RenderTargetCache,
+    bindgroup_cache: BindGroupCache,
+    material_cache: MaterialCache,
+}
+
+impl State {
+    fn configure_surface(&self) {
+        if let Some(surface) = self.surface.as_ref() {
+            surface.configure(&self.device, &self.surface_config);
+        }
+    }
+
+    pub fn size(&self) -> winit::dpi::PhysicalSize<u32> {
+        self.size
+    }
+
+    pub fn resize(
+        &mut self,
+        new_size: winit::dpi::PhysicalSize<u32>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Minimized windows report a zero size, which can't be used for a surface
+        if new_size.width == 0 || new_size.height == 0 || new_size == self.size {
+            return Ok(());
+        }
+
+        self.size = new_size;
+        self.surface_config.width = new_size.width;
+        self.surface_config.height = new_size.height;
+        self.configure_surface();
+
+        self.init_builtin_render_targets()?;
+        self.rendertarget_cache
+            .update_window_relative_render_targets(&self.device, new_size);
+
+        Ok(())
+    }
 }
 
 pub fn do_frame<'a, T: 'a>(
@@ -39,7 +92,18 @@ where
         return Ok(());
     }
 
-    state.current_surface_texture = Some(state.surface.get_current_texture()?.into());
+    state.prewarm_pipelines();
+
+    if let Some(surface) = state.surface.as_ref() {
+        match surface.get_current_texture() {
+            Ok(surface_texture) => state.current_surface_texture = Some(surface_texture),
+            Err(wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated) => {
+                state.configure_surface();
+                return Ok(());
+            }
+            Err(e) => Err(e)?,
+        }
+    }
 
     let mut encoder = state
         .device
@@ -51,7 +115,15 @@ where
 
     let command_buffer = encoder.finish();
     state.queue.submit(std::iter::once(command_buffer));
-    state.current_surface_texture.take().unwrap().present();
+    if let Some(surface_texture) = state.current_surface_texture.take() {
+        surface_texture.present();
+    }
+
+    // Losing the cache only costs a hitch next run
+    #[cfg(not(target_family = "wasm"))]
+    if let Err(e) = state.save_pipeline_cache() {
+        eprintln!("Failed to save pipeline cache: {}", e);
+    }
 
     Ok(())
 }
@@ -1,139 +1,1196 @@
+use std::{collections::HashMap, ops::Range};
+
 use crate::data;
 
+use super::{
+    animation::{Animation, ChannelOutputs, ChannelValue},
+    bindgroup::{
+        BindGroupLayoutKey, ModelBindGroup, MorphTargets, SkinBindGroup, MAX_JOINTS,
+        MAX_MORPH_TARGETS,
+    },
+    camera::Camera,
+    material::{AlphaMode, Material, MaterialDescriptor, MaterialTexture},
+    meshprocessing::{self, MeshProcessingOptions, NormalGeneration},
+    pipelinestate::{IndexFormat, PipelineVariant, PrimitiveTopology},
+    renderpass::RenderPassEncoder,
+    shader::VertexShaderKey,
+    texture::gltf_image_to_rgba8,
+};
+
+// glTF vertex attributes, vertex shaders read them from these locations. Matches the vertex inputs
+// in gltf.wgsl and pbr.wgsl.
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug)]
+pub enum VertexAttribute {
+    Position,
+    Normal,
+    Tangent,
+    TexCoord0,
+    TexCoord1,
+    Color0,
+    Joints0,
+    Weights0,
+}
+
+impl VertexAttribute {
+    pub fn from_shader_location(location: u32) -> Option<Self> {
+        match location {
+            0 => Some(VertexAttribute::Position),
+            1 => Some(VertexAttribute::Normal),
+            2 => Some(VertexAttribute::Tangent),
+            3 => Some(VertexAttribute::TexCoord0),
+            4 => Some(VertexAttribute::TexCoord1),
+            5 => Some(VertexAttribute::Color0),
+            6 => Some(VertexAttribute::Joints0),
+            7 => Some(VertexAttribute::Weights0),
+            _ => None,
+        }
+    }
+
+    // Joints are integers, everything else is read as floats. Shaders can read fewer
+    // components than the attribute has.
+    fn is_readable_as(self, format: wgpu::VertexFormat) -> bool {
+        use wgpu::VertexFormat;
+
+        match self {
+            VertexAttribute::Joints0 => matches!(
+                format,
+                VertexFormat::Uint32
+                    | VertexFormat::Uint32x2
+                    | VertexFormat::Uint32x3
+                    | VertexFormat::Uint32x4
+                    | VertexFormat::Sint32
+                    | VertexFormat::Sint32x2
+                    | VertexFormat::Sint32x3
+                    | VertexFormat::Sint32x4
+            ),
+            _ => matches!(
+                format,
+                VertexFormat::Float32
+                    | VertexFormat::Float32x2
+                    | VertexFormat::Float32x3
+                    | VertexFormat::Float32x4
+            ),
+        }
+    }
+}
+
+// Every attribute is widened to four components when it's read
+enum VertexAttributeValues {
+    Float(Vec<[f32; 4]>),
+    Uint(Vec<[u32; 4]>),
+}
+
+impl VertexAttributeValues {
+    // Used for attributes the primitive doesn't have
+    fn default_values(attribute: VertexAttribute, vertex_count: usize) -> Self {
+        match attribute {
+            VertexAttribute::Color0 => VertexAttributeValues::Float(vec![[1.0; 4]; vertex_count]),
+            VertexAttribute::Joints0 => VertexAttributeValues::Uint(vec![[0; 4]; vertex_count]),
+            _ => VertexAttributeValues::Float(vec![[0.0; 4]; vertex_count]),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            VertexAttributeValues::Float(values) => values.len(),
+            VertexAttributeValues::Uint(values) => values.len(),
+        }
+    }
+
+    fn bits(&self, vertex: usize) -> [u32; 4] {
+        match self {
+            VertexAttributeValues::Float(values) => values[vertex].map(f32::to_bits),
+            VertexAttributeValues::Uint(values) => values[vertex],
+        }
+    }
+
+    fn remap(&self, vertices: &[u32]) -> Self {
+        match self {
+            VertexAttributeValues::Float(values) => VertexAttributeValues::Float(
+                vertices
+                    .iter()
+                    .map(|vertex| values[*vertex as usize])
+                    .collect(),
+            ),
+            VertexAttributeValues::Uint(values) => VertexAttributeValues::Uint(
+                vertices
+                    .iter()
+                    .map(|vertex| values[*vertex as usize])
+                    .collect(),
+            ),
+        }
+    }
+}
+
+// A primitive's vertices while it's imported
+struct PrimitiveVertices {
+    // Only the attributes the primitive has, until the missing ones are generated or defaulted
+    attributes: HashMap<VertexAttribute, VertexAttributeValues>,
+    // Position, normal and tangent deltas of each morph target
+    morph_targets: Vec<[Vec<[f32; 3]>; 3]>,
+}
+
+impl PrimitiveVertices {
+    fn vertex_count(&self) -> usize {
+        self.attributes[&VertexAttribute::Position].len()
+    }
+
+    fn float_values(&self, attribute: VertexAttribute) -> Option<&[[f32; 4]]> {
+        match self.attributes.get(&attribute)? {
+            VertexAttributeValues::Float(values) => Some(values),
+            VertexAttributeValues::Uint(_) => None,
+        }
+    }
+
+    fn vectors(&self, attribute: VertexAttribute) -> Vec<na::Vector3<f32>> {
+        self.float_values(attribute)
+            .unwrap_or_default()
+            .iter()
+            .map(|[x, y, z, _]| na::Vector3::new(*x, *y, *z))
+            .collect()
+    }
+
+    // Vertex i becomes vertex vertices[i]
+    fn remap(&mut self, vertices: &[u32]) {
+        for values in self.attributes.values_mut() {
+            *values = values.remap(vertices);
+        }
+        for deltas in self.morph_targets.iter_mut().flatten() {
+            *deltas = vertices
+                .iter()
+                .map(|vertex| deltas[*vertex as usize])
+                .collect();
+        }
+    }
+
+    // Generates missing normals and tangents, then welds and reorders vertices as the options ask.
+    // Flat normals split the vertices, so the primitive becomes a non-indexed triangle list.
+    fn process(
+        &mut self,
+        topology: &mut PrimitiveTopology,
+        indices: &mut Option<Vec<u32>>,
+        vertex_layout: &[(VertexAttribute, wgpu::VertexAttribute)],
+        options: &MeshProcessingOptions,
+    ) {
+        let reads = |attribute| vertex_layout.iter().any(|(read, _)| *read == attribute);
+        let has = |vertices: &Self, attribute| vertices.attributes.contains_key(&attribute);
+
+        let mut triangles = meshprocessing::triangles(
+            *topology,
+            &indices
+                .clone()
+                .unwrap_or_else(|| (0..self.vertex_count() as u32).collect()),
+        );
+
+        let generate_tangents = options.generate_tangents
+            && reads(VertexAttribute::Tangent)
+            && !has(self, VertexAttribute::Tangent)
+            && has(self, VertexAttribute::TexCoord0)
+            && !triangles.is_empty();
+        let generate_normals = (reads(VertexAttribute::Normal) || generate_tangents)
+            && !has(self, VertexAttribute::Normal)
+            && !triangles.is_empty();
+
+        if generate_normals {
+            if options.normals == NormalGeneration::Flat {
+                self.remap(&meshprocessing::split_vertices(&triangles));
+                *topology = PrimitiveTopology::TriangleList;
+                *indices = None;
+                triangles = (0..triangles.len() as u32)
+                    .map(|triangle| [triangle * 3, triangle * 3 + 1, triangle * 3 + 2])
+                    .collect();
+            }
+
+            let positions = self.vectors(VertexAttribute::Position);
+            let normals = match options.normals {
+                NormalGeneration::Flat => meshprocessing::flat_normals(&positions, &triangles),
+                NormalGeneration::Smooth => meshprocessing::smooth_normals(&positions, &triangles),
+            };
+            self.attributes.insert(
+                VertexAttribute::Normal,
+                VertexAttributeValues::Float(
+                    normals
+                        .iter()
+                        .map(|normal| [normal.x, normal.y, normal.z, 0.0])
+                        .collect(),
+                ),
+            );
+        }
+
+        if generate_tangents {
+            let tex_coords: Vec<na::Vector2<f32>> = self
+                .float_values(VertexAttribute::TexCoord0)
+                .unwrap_or_default()
+                .iter()
+                .map(|[u, v, _, _]| na::Vector2::new(*u, *v))
+                .collect();
+            let tangents = meshprocessing::tangents(
+                &self.vectors(VertexAttribute::Position),
+                &self.vectors(VertexAttribute::Normal),
+                &tex_coords,
+                &triangles,
+            );
+            self.attributes.insert(
+                VertexAttribute::Tangent,
+                VertexAttributeValues::Float(
+                    tangents.iter().map(|tangent| (*tangent).into()).collect(),
+                ),
+            );
+        }
+
+        let vertex_count = self.vertex_count();
+        for (attribute, _) in vertex_layout {
+            self.attributes
+                .entry(*attribute)
+                .or_insert_with(|| VertexAttributeValues::default_values(*attribute, vertex_count));
+        }
+
+        // Only what ends up in the vertex buffer has to match
+        if options.weld_vertices {
+            let (unique_vertices, welded_vertices) =
+                meshprocessing::weld_vertices((0..vertex_count).map(|vertex| {
+                    let attribute_bits = vertex_layout
+                        .iter()
+                        .map(|(attribute, _)| self.attributes[attribute].bits(vertex));
+                    let delta_bits = self
+                        .morph_targets
+                        .iter()
+                        .flatten()
+                        .map(|deltas| deltas[vertex].map(f32::to_bits))
+                        .map(|[x, y, z]| [x, y, z, 0]);
+                    attribute_bits.chain(delta_bits).collect::<Vec<[u32; 4]>>()
+                }));
+
+            if unique_vertices.len() < vertex_count {
+                self.remap(&unique_vertices);
+                *indices = Some(match indices.as_ref() {
+                    Some(indices) => indices
+                        .iter()
+                        .map(|index| welded_vertices[*index as usize])
+                        .collect(),
+                    None => welded_vertices,
+                });
+            }
+        }
+
+        if options.optimize_indices && *topology == PrimitiveTopology::TriangleList {
+            if let Some(indices) = indices.as_mut() {
+                *indices = meshprocessing::optimize_vertex_cache(indices, self.vertex_count());
+            }
+        }
+    }
+
+    // Appends the vertices in the vertex shader's layout, only the attributes the shader reads
+    // are written
+    fn write(
+        &self,
+        vertex_layout: &[(VertexAttribute, wgpu::VertexAttribute)],
+        vertices_bytes: &mut Vec<u8>,
+    ) {
+        for vertex in 0..self.vertex_count() {
+            for (attribute, shader_attribute) in vertex_layout {
+                let component_count = (shader_attribute.format.size() / 4) as usize;
+                match &self.attributes[attribute] {
+                    VertexAttributeValues::Float(values) => vertices_bytes.extend_from_slice(
+                        bytemuck::cast_slice(&values[vertex][..component_count]),
+                    ),
+                    VertexAttributeValues::Uint(values) => vertices_bytes.extend_from_slice(
+                        bytemuck::cast_slice(&values[vertex][..component_count]),
+                    ),
+                }
+            }
+        }
+    }
+
+    // Appends the position, normal and tangent deltas of every morph target for each vertex, in
+    // the layout include/morphing.wgsl reads
+    fn write_morph_deltas(&self, morph_deltas: &mut Vec<[f32; 4]>) {
+        for vertex in 0..self.vertex_count() {
+            for target in &self.morph_targets {
+                for deltas in target {
+                    let [x, y, z] = deltas[vertex];
+                    morph_deltas.push([x, y, z, 0.0]);
+                }
+            }
+        }
+    }
+}
+
+pub struct Primitive {
+    pub topology: PrimitiveTopology,
+    pub base_vertex: i32,
+    pub vertex_count: u32,
+    // None for primitives drawn without indices
+    pub index_range: Option<Range<u32>>,
+    pub material: usize,
+    // Center of the primitive's bounds, blended primitives are sorted by it
+    pub center: na::Point3<f32>,
+}
+
+pub struct Mesh {
+    pub name: String,
+    pub primitives: Vec<Primitive>,
+    // Every primitive of a mesh has the same morph targets
+    pub morph_targets: MorphTargets,
+    pub morph_weights: Vec<f32>,
+}
+
+pub struct Skin {
+    pub name: String,
+    pub joints: Vec<usize>,
+    pub inverse_bind_matrices: Vec<na::Matrix4<f32>>,
+    pub bind_group: SkinBindGroup,
+}
+
+pub struct Node {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub mesh: Option<usize>,
+    pub skin: Option<usize>,
+    // Animations write these, `transform` is rebuilt from them by `animate`
+    pub translation: na::Vector3<f32>,
+    pub rotation: na::UnitQuaternion<f32>,
+    pub scale: na::Vector3<f32>,
+    // Set by animations or game code, call `update_bind_groups` afterwards
+    pub morph_weights: Vec<f32>,
+    pub transform: na::Matrix4<f32>,
+    pub world_transform: na::Matrix4<f32>,
+    // Only nodes with a mesh get a bind group
+    pub bind_group: Option<ModelBindGroup>,
+}
+
 #[derive(Default)]
 pub struct Model {
+    pub name: String,
+    // Vertices are laid out for the vertex shader the model was imported for
     pub vertex_buffer: Option<wgpu::Buffer>,
+    // None if no primitive has indices
     pub index_buffer: Option<wgpu::Buffer>,
+    pub index_format: IndexFormat,
+    pub morph_delta_texture: Option<wgpu::Texture>,
     pub textures: Vec<wgpu::Texture>,
+    pub samplers: Vec<wgpu::Sampler>,
+    pub materials: Vec<Material>,
+    pub meshes: Vec<Mesh>,
+    pub skins: Vec<Skin>,
+    pub animations: Vec<Animation>,
+    pub nodes: Vec<Node>,
+    pub root_nodes: Vec<usize>,
 }
 
 impl Model {
+    // The model can be drawn with `vs` and with other vertex shaders that read the same vertex
+    // inputs
     pub async fn from_gltf(
-        &mut self,
         path: &str,
+        vs: VertexShaderKey,
+        state: &super::State,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::from_gltf_with_options(path, vs, &MeshProcessingOptions::default(), state).await
+    }
+
+    pub async fn from_gltf_with_options(
+        path: &str,
+        vs: VertexShaderKey,
+        options: &MeshProcessingOptions,
         state: &super::State,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let buffer = data::read_bytes(path).await?;
         let (gltf_doc, gltf_buffers, gltf_images) = gltf::import_slice(buffer.as_slice())
             .map_err(|_| format!("Invalid gltf file: {}", path))?;
 
-        let mesh = gltf_doc.meshes().nth(0).ok_or(format!(
-            "gltf file must contain at least one mesh: {}",
-            path
-        ))?;
+        if gltf_doc.meshes().len() == 0 {
+            Err(format!(
+                "gltf file must contain at least one mesh: {}",
+                path
+            ))?
+        }
 
-        let mesh_name = mesh.name().or(Some(&path)).unwrap();
+        let mut model = Model {
+            name: path.to_string(),
+            ..Default::default()
+        };
 
-        for primitive in mesh.primitives().nth(0) {
-            let mode = primitive.mode();
-            assert!(mode == gltf::json::mesh::Mode::Triangles);
+        model.samplers = gltf_doc
+            .samplers()
+            .map(|sampler| state.create_gltf_sampler(&sampler))
+            .collect();
 
-            let reader = primitive.reader(|buffer| Some(&gltf_buffers[buffer.index()]));
+        let material_descriptors: Vec<MaterialDescriptor> = {
+            let mut texture_indices: HashMap<(usize, bool), usize> = HashMap::new();
 
-            let indices: Vec<u32> = reader.read_indices().unwrap().into_u32().collect();
+            let mut import_texture =
+                |texture: gltf::texture::Texture,
+                 tex_coord: u32,
+                 srgb: bool|
+                 -> Result<MaterialTexture, Box<dyn std::error::Error>> {
+                    // The vertex shaders only read the first two texture coordinate sets
+                    if tex_coord > 1 {
+                        Err(format!(
+                            "Texture {} uses TEXCOORD_{}, only TEXCOORD_0 and TEXCOORD_1 are \
+                             supported: {}",
+                            texture.index(),
+                            tex_coord,
+                            path
+                        ))?
+                    }
 
-            self.index_buffer = Some(wgpu::util::DeviceExt::create_buffer_init(
-                state.device.as_ref(),
-                &wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!(
-                        "Index buffer: {} [primitive {}]",
+                    let image_index = texture.source().index();
+                    let texture_index =
+                        *texture_indices
+                            .entry((image_index, srgb))
+                            .or_insert_with(|| {
+                                let image = &gltf_images[image_index];
+                                model.textures.push(state.create_rgba8_texture(
+                                    &format!("{} [image {}]", path, image_index),
+                                    image.width,
+                                    image.height,
+                                    &gltf_image_to_rgba8(image),
+                                    srgb,
+                                ));
+                                model.textures.len() - 1
+                            });
+
+                    Ok(MaterialTexture {
+                        texture: texture_index,
+                        sampler: texture.sampler().index(),
+                        tex_coord,
+                    })
+                };
+
+            gltf_doc
+                .materials()
+                .map(|material| {
+                    Self::gltf_material_descriptor(path, &material, &mut import_texture)
+                })
+                .collect::<Result<_, _>>()?
+        };
+
+        model.materials = material_descriptors
+            .into_iter()
+            .map(|descriptor| state.create_material(descriptor, &model.textures, &model.samplers))
+            .collect();
+
+        // Primitives without a material use the glTF default material
+        let default_material = model.materials.len();
+        model.materials.push(state.create_material(
+            MaterialDescriptor::default(),
+            &model.textures,
+            &model.samplers,
+        ));
+
+        let missing_layout = || format!("Vertex shader {:?} failed to load: {}", vs, path);
+        let vertex_stride = state
+            .find_vertex_shader_stride(vs)
+            .ok_or_else(missing_layout)? as usize;
+        let vertex_layout = state
+            .find_vertex_attributes(vs)
+            .ok_or_else(missing_layout)?
+            .iter()
+            .map(|shader_attribute| {
+                let attribute = VertexAttribute::from_shader_location(
+                    shader_attribute.shader_location,
+                )
+                .ok_or(format!(
+                    "Vertex shader {:?} reads location {}, which isn't a glTF attribute: {}",
+                    vs, shader_attribute.shader_location, path
+                ))?;
+                if !attribute.is_readable_as(shader_attribute.format) {
+                    Err(format!(
+                        "Vertex shader {:?} reads {:?} as {:?}: {}",
+                        vs, attribute, shader_attribute.format, path
+                    ))?
+                }
+                Ok((attribute, *shader_attribute))
+            })
+            .collect::<Result<Vec<_>, Box<dyn std::error::Error>>>()?;
+
+        let mut vertices_bytes = Vec::new();
+        let mut vertex_count = 0;
+        let mut indices: Vec<u32> = Vec::new();
+        let mut morph_deltas: Vec<[f32; 4]> = Vec::new();
+
+        for mesh in gltf_doc.meshes() {
+            let mesh_name = mesh
+                .name()
+                .map_or(format!("{} [mesh {}]", path, mesh.index()), str::to_string);
+
+            let mut primitives = Vec::with_capacity(mesh.primitives().len());
+            let first_morph_delta = morph_deltas.len();
+            let mut morph_target_count = None;
+
+            for primitive in mesh.primitives() {
+                let reader = primitive.reader(|buffer| Some(&gltf_buffers[buffer.index()]));
+                let bounding_box = primitive.bounding_box();
+
+                let mut vertices =
+                    Self::read_gltf_vertices(&reader, &vertex_layout).ok_or(format!(
+                        "Primitive has no positions, or attributes or morph targets with \
+                         different vertex counts: {} [primitive {}]",
                         mesh_name,
                         primitive.index()
-                    )),
-                    contents: bytemuck::cast_slice(indices.as_slice()),
-                    usage: wgpu::BufferUsages::INDEX,
-                },
-            ));
+                    ))?;
 
-            let normals_default = dyn_iter::DynIter::new(std::iter::repeat([0_f32, 0_f32, 0_f32]));
-            let tangents_default =
-                dyn_iter::DynIter::new(std::iter::repeat([0_f32, 0_f32, 0_f32, 0_f32]));
-            let tex_coords_0_default = dyn_iter::DynIter::new(std::iter::repeat([0_f32, 0_f32]));
-            let tex_coords_1_default = dyn_iter::DynIter::new(std::iter::repeat([0_f32, 0_f32]));
-            let colors_0_default =
-                dyn_iter::DynIter::new(std::iter::repeat([1_f32, 1_f32, 1_f32, 1_f32]));
-            let joints_0_default =
-                dyn_iter::DynIter::new(std::iter::repeat([0_u16, 0_u16, 0_u16, 0_u16]));
-            let weights_0_default =
-                dyn_iter::DynIter::new(std::iter::repeat([0_f32, 0_f32, 0_f32, 0_f32]));
-
-            let positions = reader.read_positions().unwrap();
-            let normals = reader
-                .read_normals()
-                .map_or(normals_default, |iter| dyn_iter::DynIter::new(iter));
-            let tangents = reader
-                .read_tangents()
-                .map_or(tangents_default, |iter| dyn_iter::DynIter::new(iter));
-            let tex_coords_0 = reader
-                .read_tex_coords(0)
-                .map_or(tex_coords_0_default, |iter| {
-                    dyn_iter::DynIter::new(iter.into_f32())
+                let primitive_morph_target_count = vertices.morph_targets.len();
+                if *morph_target_count.get_or_insert(primitive_morph_target_count)
+                    != primitive_morph_target_count
+                {
+                    Err(format!(
+                        "Primitives have different morph targets: {} [primitive {}]",
+                        mesh_name,
+                        primitive.index()
+                    ))?
+                }
+
+                let (mut topology, mut primitive_indices) = Self::gltf_primitive_topology(
+                    primitive.mode(),
+                    reader
+                        .read_indices()
+                        .map(|indices| indices.into_u32().collect()),
+                    vertices.vertex_count(),
+                );
+                if primitive_indices
+                    .as_ref()
+                    .map_or(false, |primitive_indices| {
+                        primitive_indices
+                            .iter()
+                            .any(|index| *index as usize >= vertices.vertex_count())
+                    })
+                {
+                    Err(format!(
+                        "Primitive has indices past its vertices: {} [primitive {}]",
+                        mesh_name,
+                        primitive.index()
+                    ))?
+                }
+
+                vertices.process(
+                    &mut topology,
+                    &mut primitive_indices,
+                    &vertex_layout,
+                    options,
+                );
+
+                let base_vertex = vertex_count as i32;
+                let primitive_vertex_count = vertices.vertex_count();
+                vertices.write(&vertex_layout, &mut vertices_bytes);
+                vertices.write_morph_deltas(&mut morph_deltas);
+                vertex_count += primitive_vertex_count;
+
+                assert!(vertices_bytes.len() == vertex_count * vertex_stride);
+
+                let index_range = primitive_indices.map(|primitive_indices| {
+                    let first_index = indices.len() as u32;
+                    indices.extend(primitive_indices);
+                    first_index..indices.len() as u32
                 });
-            let tex_coords_1 = reader
-                .read_tex_coords(0)
-                .map_or(tex_coords_1_default, |iter| {
-                    dyn_iter::DynIter::new(iter.into_f32())
+
+                primitives.push(Primitive {
+                    topology,
+                    base_vertex,
+                    vertex_count: primitive_vertex_count as u32,
+                    index_range,
+                    material: primitive.material().index().unwrap_or(default_material),
+                    center: na::center(
+                        &na::Point3::from(bounding_box.min),
+                        &na::Point3::from(bounding_box.max),
+                    ),
                 });
-            let colors_0 = reader.read_colors(0).map_or(colors_0_default, |iter| {
-                dyn_iter::DynIter::new(iter.into_rgba_f32())
-            });
-            let joints_0 = reader.read_joints(0).map_or(joints_0_default, |iter| {
-                dyn_iter::DynIter::new(iter.into_u16())
-            });
-            let weights_0 = reader.read_weights(0).map_or(weights_0_default, |iter| {
-                dyn_iter::DynIter::new(iter.into_f32())
+            }
+
+            let morph_target_count = morph_target_count.unwrap_or(0);
+            if morph_target_count > MAX_MORPH_TARGETS {
+                Err(format!(
+                    "Mesh has {} morph targets, at most {} are supported: {}",
+                    morph_target_count, MAX_MORPH_TARGETS, mesh_name
+                ))?
+            }
+
+            // Missing default weights are zero
+            let mut morph_weights = mesh.weights().unwrap_or_default().to_vec();
+            morph_weights.resize(morph_target_count, 0.0);
+
+            model.meshes.push(Mesh {
+                name: mesh_name,
+                morph_targets: MorphTargets {
+                    target_count: morph_target_count as u32,
+                    first_vertex: primitives
+                        .first()
+                        .map_or(0, |primitive| primitive.base_vertex as u32),
+                    first_delta: first_morph_delta as u32,
+                },
+                morph_weights,
+                primitives,
             });
+        }
+
+        // Indices are relative to the primitive's base vertex, so most models fit in 16 bits.
+        // 0xffff is left out, strips treat it as a primitive restart.
+        let indices_bytes = if indices.iter().all(|index| *index < u16::MAX as u32) {
+            model.index_format = IndexFormat::Uint16;
+            let indices: Vec<u16> = indices.iter().map(|index| *index as u16).collect();
+            bytemuck::cast_slice(indices.as_slice()).to_vec()
+        } else {
+            model.index_format = IndexFormat::Uint32;
+            bytemuck::cast_slice(indices.as_slice()).to_vec()
+        };
 
-            let desired_capacity = positions.len()
-                * state.find_vertex_shader_stride(crate::gfx::shader::VertexShaderKey::GltfVS)
-                    as usize;
-
-            let mut vertices_bytes = Vec::with_capacity(desired_capacity);
-            for (position, normal, tangent, tex_coord_0, tex_coord_1, color_0, joint_0, weight_0) in
-                itertools::multizip((
-                    positions,
-                    normals,
-                    tangents,
-                    tex_coords_0,
-                    tex_coords_1,
-                    colors_0,
-                    joints_0,
-                    weights_0,
-                ))
-            {
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&position));
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&normal));
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&tangent));
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&tex_coord_0));
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&tex_coord_1));
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&color_0));
-                let joint_0_u32 = [
-                    joint_0[0] as u32,
-                    joint_0[1] as u32,
-                    joint_0[2] as u32,
-                    joint_0[3] as u32,
-                ];
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&joint_0_u32));
-                vertices_bytes.extend_from_slice(bytemuck::bytes_of(&weight_0));
-            }
-
-            assert!(vertices_bytes.len() == desired_capacity);
-
-            self.vertex_buffer = Some(wgpu::util::DeviceExt::create_buffer_init(
+        if !indices.is_empty() {
+            model.index_buffer = Some(wgpu::util::DeviceExt::create_buffer_init(
                 state.device.as_ref(),
                 &wgpu::util::BufferInitDescriptor {
-                    label: Some(&format!(
-                        "Vertex buffer: {} [primitive {}]",
-                        mesh_name,
-                        primitive.index()
-                    )),
-                    contents: bytemuck::cast_slice(vertices_bytes.as_slice()),
-                    usage: wgpu::BufferUsages::VERTEX,
+                    label: Some(&format!("Index buffer: {}", path)),
+                    contents: &indices_bytes,
+                    usage: wgpu::BufferUsages::INDEX,
                 },
             ));
         }
 
-        let model = Model::default();
+        model.vertex_buffer = Some(wgpu::util::DeviceExt::create_buffer_init(
+            state.device.as_ref(),
+            &wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("Vertex buffer: {}", path)),
+                contents: bytemuck::cast_slice(vertices_bytes.as_slice()),
+                usage: wgpu::BufferUsages::VERTEX,
+            },
+        ));
+
+        if !morph_deltas.is_empty() {
+            model.morph_delta_texture =
+                Some(state.create_morph_delta_texture(
+                    &format!("Morph deltas: {}", path),
+                    &morph_deltas,
+                )?);
+        }
+
+        model.nodes = gltf_doc
+            .nodes()
+            .map(|node| {
+                let (translation, [x, y, z, w], scale) = node.transform().decomposed();
+
+                // Nodes override the default weights of their mesh
+                let mut morph_weights = node.mesh().map_or(Vec::new(), |mesh| {
+                    model.meshes[mesh.index()].morph_weights.clone()
+                });
+                for (weight, node_weight) in morph_weights
+                    .iter_mut()
+                    .zip(node.weights().unwrap_or_default())
+                {
+                    *weight = *node_weight;
+                }
+
+                Node {
+                    name: node
+                        .name()
+                        .map_or(format!("node {}", node.index()), str::to_string),
+                    parent: None,
+                    children: node.children().map(|child| child.index()).collect(),
+                    mesh: node.mesh().map(|mesh| mesh.index()),
+                    skin: node.skin().map(|skin| skin.index()),
+                    translation: translation.into(),
+                    rotation: na::UnitQuaternion::new_normalize(na::Quaternion::new(w, x, y, z)),
+                    scale: scale.into(),
+                    morph_weights,
+                    transform: na::Matrix4::from(node.transform().matrix()),
+                    world_transform: na::Matrix4::identity(),
+                    bind_group: None,
+                }
+            })
+            .collect();
+
+        for node_index in 0..model.nodes.len() {
+            for child_index in model.nodes[node_index].children.clone() {
+                model.nodes[child_index].parent = Some(node_index);
+            }
+        }
+
+        model.root_nodes = match gltf_doc.default_scene().or(gltf_doc.scenes().nth(0)) {
+            Some(scene) => scene.nodes().map(|node| node.index()).collect(),
+            None => (0..model.nodes.len())
+                .filter(|node_index| model.nodes[*node_index].parent.is_none())
+                .collect(),
+        };
+
+        model.animations = gltf_doc
+            .animations()
+            .map(|animation| Animation::from_gltf(path, &animation, &gltf_buffers))
+            .collect::<Result<_, _>>()?;
+
+        model.update_world_transforms();
+
+        let morph_delta_texture_view = model
+            .morph_delta_texture
+            .as_ref()
+            .map(|texture| texture.create_view(&wgpu::TextureViewDescriptor::default()));
+
+        for node in model.nodes.iter_mut() {
+            if let Some(mesh_index) = node.mesh {
+                let morph_targets = model.meshes[mesh_index].morph_targets;
+                node.bind_group = Some(
+                    state.create_model_bind_group(
+                        &format!("{} [{}]", path, node.name),
+                        &node.world_transform,
+                        morph_targets,
+                        &node.morph_weights,
+                        morph_delta_texture_view
+                            .as_ref()
+                            .filter(|_| morph_targets.target_count > 0),
+                    ),
+                );
+            }
+        }
+
+        for skin in gltf_doc.skins() {
+            let name = skin
+                .name()
+                .map_or(format!("{} [skin {}]", path, skin.index()), str::to_string);
+
+            let joints: Vec<usize> = skin.joints().map(|joint| joint.index()).collect();
+            if joints.len() > MAX_JOINTS {
+                Err(format!(
+                    "Skin has {} joints, at most {} are supported: {}",
+                    joints.len(),
+                    MAX_JOINTS,
+                    name
+                ))?
+            }
+
+            // Missing inverse bind matrices are identity matrices
+            let inverse_bind_matrices = skin
+                .reader(|buffer| Some(&gltf_buffers[buffer.index()]))
+                .read_inverse_bind_matrices()
+                .map_or(vec![na::Matrix4::identity(); joints.len()], |iter| {
+                    iter.map(|matrix| na::Matrix4::from(matrix)).collect()
+                });
+            if inverse_bind_matrices.len() < joints.len() {
+                Err(format!(
+                    "Skin has {} inverse bind matrices for {} joints: {}",
+                    inverse_bind_matrices.len(),
+                    joints.len(),
+                    name
+                ))?
+            }
+
+            let joint_matrices =
+                Self::joint_matrices(&model.nodes, &joints, &inverse_bind_matrices);
+            model.skins.push(Skin {
+                bind_group: state.create_skin_bind_group(&name, &joint_matrices),
+                name,
+                joints,
+                inverse_bind_matrices,
+            });
+        }
+
         Ok(model)
     }
+
+    fn gltf_material_descriptor<F>(
+        path: &str,
+        material: &gltf::Material,
+        import_texture: &mut F,
+    ) -> Result<MaterialDescriptor, Box<dyn std::error::Error>>
+    where
+        F: FnMut(
+            gltf::texture::Texture,
+            u32,
+            bool,
+        ) -> Result<MaterialTexture, Box<dyn std::error::Error>>,
+    {
+        let pbr = material.pbr_metallic_roughness();
+
+        Ok(MaterialDescriptor {
+            name: material.name().map_or(
+                format!("{} [material {}]", path, material.index().unwrap_or(0)),
+                str::to_string,
+            ),
+            base_color_factor: pbr.base_color_factor(),
+            base_color_texture: pbr
+                .base_color_texture()
+                .map(|info| import_texture(info.texture(), info.tex_coord(), true))
+                .transpose()?,
+            metallic_factor: pbr.metallic_factor(),
+            roughness_factor: pbr.roughness_factor(),
+            metallic_roughness_texture: pbr
+                .metallic_roughness_texture()
+                .map(|info| import_texture(info.texture(), info.tex_coord(), false))
+                .transpose()?,
+            normal_scale: material.normal_texture().map_or(1.0, |info| info.scale()),
+            normal_texture: material
+                .normal_texture()
+                .map(|info| import_texture(info.texture(), info.tex_coord(), false))
+                .transpose()?,
+            occlusion_strength: material
+                .occlusion_texture()
+                .map_or(1.0, |info| info.strength()),
+            occlusion_texture: material
+                .occlusion_texture()
+                .map(|info| import_texture(info.texture(), info.tex_coord(), false))
+                .transpose()?,
+            emissive_factor: material.emissive_factor(),
+            emissive_texture: material
+                .emissive_texture()
+                .map(|info| import_texture(info.texture(), info.tex_coord(), true))
+                .transpose()?,
+            alpha_mode: match material.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                gltf::material::AlphaMode::Mask => AlphaMode::Mask {
+                    cutoff: material.alpha_cutoff().unwrap_or(0.5),
+                },
+                gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+            },
+            double_sided: material.double_sided(),
+        })
+    }
+
+    // wgpu has no fans or loops, they're converted to indexed lists. The indices are None for
+    // primitives drawn without them.
+    fn gltf_primitive_topology(
+        mode: gltf::json::mesh::Mode,
+        indices: Option<Vec<u32>>,
+        vertex_count: usize,
+    ) -> (PrimitiveTopology, Option<Vec<u32>>) {
+        use gltf::json::mesh::Mode;
+
+        let list_indices = || {
+            indices
+                .clone()
+                .unwrap_or_else(|| (0..vertex_count as u32).collect())
+        };
+
+        match mode {
+            Mode::Points => (PrimitiveTopology::PointList, indices),
+            Mode::Lines => (PrimitiveTopology::LineList, indices),
+            Mode::LineStrip => (PrimitiveTopology::LineStrip, indices),
+            Mode::Triangles => (PrimitiveTopology::TriangleList, indices),
+            Mode::TriangleStrip => (PrimitiveTopology::TriangleStrip, indices),
+            Mode::LineLoop => {
+                let loop_indices = list_indices();
+                let line_indices = match loop_indices.len() {
+                    0 | 1 => Vec::new(),
+                    count => (0..count)
+                        .flat_map(|i| [loop_indices[i], loop_indices[(i + 1) % count]])
+                        .collect(),
+                };
+                (PrimitiveTopology::LineList, Some(line_indices))
+            }
+            Mode::TriangleFan => {
+                let fan_indices = list_indices();
+                let triangle_indices = (1..fan_indices.len().saturating_sub(1))
+                    .flat_map(|i| [fan_indices[0], fan_indices[i], fan_indices[i + 1]])
+                    .collect();
+                (PrimitiveTopology::TriangleList, Some(triangle_indices))
+            }
+        }
+    }
+
+    // None if the primitive doesn't have the attribute
+    fn read_gltf_vertex_attribute<'a, 's, F>(
+        reader: &gltf::mesh::Reader<'a, 's, F>,
+        attribute: VertexAttribute,
+    ) -> Option<VertexAttributeValues>
+    where
+        F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+    {
+        Some(match attribute {
+            VertexAttribute::Position => VertexAttributeValues::Float(
+                reader
+                    .read_positions()?
+                    .map(|[x, y, z]| [x, y, z, 1.0])
+                    .collect(),
+            ),
+            VertexAttribute::Normal => VertexAttributeValues::Float(
+                reader
+                    .read_normals()?
+                    .map(|[x, y, z]| [x, y, z, 0.0])
+                    .collect(),
+            ),
+            VertexAttribute::Tangent => {
+                VertexAttributeValues::Float(reader.read_tangents()?.collect())
+            }
+            VertexAttribute::TexCoord0 | VertexAttribute::TexCoord1 => {
+                let set = match attribute {
+                    VertexAttribute::TexCoord0 => 0,
+                    _ => 1,
+                };
+                VertexAttributeValues::Float(
+                    reader
+                        .read_tex_coords(set)?
+                        .into_f32()
+                        .map(|[u, v]| [u, v, 0.0, 0.0])
+                        .collect(),
+                )
+            }
+            VertexAttribute::Color0 => {
+                VertexAttributeValues::Float(reader.read_colors(0)?.into_rgba_f32().collect())
+            }
+            VertexAttribute::Joints0 => VertexAttributeValues::Uint(
+                reader
+                    .read_joints(0)?
+                    .into_u16()
+                    .map(|joint| joint.map(u32::from))
+                    .collect(),
+            ),
+            VertexAttribute::Weights0 => {
+                VertexAttributeValues::Float(reader.read_weights(0)?.into_f32().collect())
+            }
+        })
+    }
+
+    // Reads the attributes the vertex shader needs, and the ones needed to generate them. None if
+    // there are no positions or the attributes and morph targets have different vertex counts.
+    fn read_gltf_vertices<'a, 's, F>(
+        reader: &gltf::mesh::Reader<'a, 's, F>,
+        vertex_layout: &[(VertexAttribute, wgpu::VertexAttribute)],
+    ) -> Option<PrimitiveVertices>
+    where
+        F: Clone + Fn(gltf::Buffer<'a>) -> Option<&'s [u8]>,
+    {
+        let mut attributes: Vec<VertexAttribute> = vertex_layout
+            .iter()
+            .map(|(attribute, _)| *attribute)
+            .collect();
+        attributes.push(VertexAttribute::Position);
+        if attributes.contains(&VertexAttribute::Tangent) {
+            attributes.extend([VertexAttribute::Normal, VertexAttribute::TexCoord0]);
+        }
+
+        let mut vertices = PrimitiveVertices {
+            attributes: HashMap::new(),
+            morph_targets: Vec::new(),
+        };
+        for attribute in attributes {
+            if let Some(values) = Self::read_gltf_vertex_attribute(reader, attribute) {
+                vertices.attributes.insert(attribute, values);
+            }
+        }
+
+        let vertex_count = vertices.attributes.get(&VertexAttribute::Position)?.len();
+
+        // Missing deltas are zero
+        let zeros = || vec![[0_f32, 0_f32, 0_f32]; vertex_count];
+        vertices.morph_targets = reader
+            .read_morph_targets()
+            .map(|(positions, normals, tangents)| {
+                [
+                    positions.map_or_else(zeros, Iterator::collect),
+                    normals.map_or_else(zeros, Iterator::collect),
+                    tangents.map_or_else(zeros, Iterator::collect),
+                ]
+            })
+            .collect();
+
+        if vertices
+            .attributes
+            .values()
+            .any(|values| values.len() != vertex_count)
+            || vertices
+                .morph_targets
+                .iter()
+                .flatten()
+                .any(|deltas| deltas.len() != vertex_count)
+        {
+            return None;
+        }
+
+        Some(vertices)
+    }
+
+    // Poses the animated nodes at `time` seconds into the animation, call
+    // `update_world_transforms` and `update_bind_groups` afterwards
+    pub fn animate(&mut self, animation_index: usize, time: f32) {
+        let animation = &self.animations[animation_index];
+
+        for channel in &animation.channels {
+            let node = &mut self.nodes[channel.node];
+            match channel.sample(time) {
+                ChannelValue::Translation(translation) => node.translation = translation,
+                ChannelValue::Rotation(rotation) => node.rotation = rotation,
+                ChannelValue::Scale(scale) => node.scale = scale,
+                ChannelValue::MorphWeights(weights) => node.morph_weights = weights,
+            }
+        }
+
+        for channel in &animation.channels {
+            if let ChannelOutputs::MorphTargetWeights(_) = channel.outputs {
+                continue;
+            }
+
+            let node = &mut self.nodes[channel.node];
+            node.transform = na::Matrix4::new_translation(&node.translation)
+                * node.rotation.to_homogeneous()
+                * na::Matrix4::new_nonuniform_scaling(&node.scale);
+        }
+    }
+
+    fn joint_matrices(
+        nodes: &[Node],
+        joints: &[usize],
+        inverse_bind_matrices: &[na::Matrix4<f32>],
+    ) -> Vec<na::Matrix4<f32>> {
+        joints
+            .iter()
+            .zip(inverse_bind_matrices)
+            .map(|(joint, inverse_bind_matrix)| nodes[*joint].world_transform * inverse_bind_matrix)
+            .collect()
+    }
+
+    pub fn update_world_transforms(&mut self) {
+        let mut nodes_to_visit: Vec<(usize, na::Matrix4<f32>)> = self
+            .root_nodes
+            .iter()
+            .map(|node_index| (*node_index, na::Matrix4::identity()))
+            .collect();
+
+        while let Some((node_index, parent_transform)) = nodes_to_visit.pop() {
+            let node = &mut self.nodes[node_index];
+            node.world_transform = parent_transform * node.transform;
+
+            for child_index in &node.children {
+                nodes_to_visit.push((*child_index, node.world_transform));
+            }
+        }
+    }
+
+    // Uploads the world transforms computed by `update_world_transforms`, the morph weights, and
+    // the joint matrices of the skins built from the transforms
+    pub fn update_bind_groups(&self, state: &super::State) {
+        for node in &self.nodes {
+            if let Some(bind_group) = node.bind_group.as_ref() {
+                state.update_model_bind_group(
+                    bind_group,
+                    &node.world_transform,
+                    &node.morph_weights,
+                );
+            }
+        }
+
+        for skin in &self.skins {
+            state.update_skin_bind_group(
+                &skin.bind_group,
+                &Self::joint_matrices(&self.nodes, &skin.joints, &skin.inverse_bind_matrices),
+            );
+        }
+    }
+
+    // Nodes reachable from the scene roots that have a mesh attached
+    pub fn mesh_nodes(&self) -> impl Iterator<Item = (&Node, &Mesh)> {
+        let mut nodes_to_visit = self.root_nodes.clone();
+        let mut mesh_nodes = Vec::new();
+
+        while let Some(node_index) = nodes_to_visit.pop() {
+            let node = &self.nodes[node_index];
+            if let Some(mesh_index) = node.mesh {
+                mesh_nodes.push((node, &self.meshes[mesh_index]));
+            }
+            nodes_to_visit.extend_from_slice(&node.children);
+        }
+
+        mesh_nodes.into_iter()
+    }
+
+    // The primitive's topology, and the blend, cull and depth write modes of its material
+    pub fn pipeline_variant(&self, primitive: &Primitive) -> PipelineVariant {
+        let material = &self.materials[primitive.material].descriptor;
+        PipelineVariant {
+            topology: primitive.topology,
+            strip_index_format: self.index_format,
+            blend_mode: material.blend_mode(),
+            cull_mode: material.cull_mode(),
+            depth_write_enabled: material.depth_write_enabled(),
+        }
+    }
+
+    // Variants the primitives are drawn with, the render pass drawing the model needs a pipeline
+    // for each, see `RenderPass::pipeline_variants`
+    pub fn pipeline_variants(&self) -> Vec<PipelineVariant> {
+        let mut variants = Vec::new();
+        for primitive in self.meshes.iter().flat_map(|mesh| &mesh.primitives) {
+            let variant = self.pipeline_variant(primitive);
+            if !variants.contains(&variant) {
+                variants.push(variant);
+            }
+        }
+        variants
+    }
+
+    fn bind_node<'a>(
+        &'a self,
+        state: &'a super::State,
+        node: &'a Node,
+        render_pass: &mut RenderPassEncoder<'a>,
+    ) {
+        render_pass.set_bind_group(
+            BindGroupLayoutKey::Model.group_index(),
+            node.bind_group.as_ref().unwrap().bind_group(),
+            &[],
+        );
+        render_pass.set_bind_group(
+            BindGroupLayoutKey::Skin.group_index(),
+            node.skin.map_or(state.default_skin_bind_group(), |skin| {
+                self.skins[skin].bind_group.bind_group()
+            }),
+            &[],
+        );
+    }
+
+    fn draw_primitive<'a>(
+        &'a self,
+        primitive: &Primitive,
+        render_pass: &mut RenderPassEncoder<'a>,
+    ) {
+        if !render_pass.set_pipeline_variant(self.pipeline_variant(primitive)) {
+            return;
+        }
+
+        render_pass.set_bind_group(
+            BindGroupLayoutKey::Material.group_index(),
+            self.materials[primitive.material].bind_group(),
+            &[],
+        );
+        match primitive.index_range.clone() {
+            Some(index_range) => render_pass.draw_indexed(index_range, primitive.base_vertex, 0..1),
+            None => {
+                let first_vertex = primitive.base_vertex as u32;
+                render_pass.draw(first_vertex..first_vertex + primitive.vertex_count, 0..1)
+            }
+        }
+    }
+
+    // Opaque and masked primitives are drawn first, then the blended ones from back to front so
+    // they blend over everything behind them. Skins are bound for the skinned vertex shaders,
+    // other shaders ignore them. Primitives with a variant the render pass has no pipeline for
+    // are skipped.
+    pub fn draw<'a>(
+        &'a self,
+        state: &'a super::State,
+        camera: &Camera,
+        render_pass: &mut RenderPassEncoder<'a>,
+    ) {
+        render_pass.set_vertex_buffer(0, self.vertex_buffer.as_ref().unwrap().slice(..));
+        if let Some(index_buffer) = self.index_buffer.as_ref() {
+            render_pass.set_index_buffer(index_buffer.slice(..), self.index_format.index_format());
+        }
+
+        let mut blended_primitives = Vec::new();
+        for (node, mesh) in self.mesh_nodes() {
+            self.bind_node(state, node, render_pass);
+
+            for primitive in &mesh.primitives {
+                if self.materials[primitive.material].descriptor.is_blended() {
+                    let center = node.world_transform.transform_point(&primitive.center);
+                    let distance = na::distance_squared(&center, &camera.eye);
+                    blended_primitives.push((distance, node, primitive));
+                } else {
+                    self.draw_primitive(primitive, render_pass);
+                }
+            }
+        }
+
+        blended_primitives.sort_by(|(a, _, _), (b, _, _)| b.total_cmp(a));
+        for (_, node, primitive) in blended_primitives {
+            self.bind_node(state, node, render_pass);
+            self.draw_primitive(primitive, render_pass);
+        }
+    }
 }
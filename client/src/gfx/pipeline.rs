@@ -0,0 +1,430 @@
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    bindgroup::BindGroupLayoutKey,
+    pipelinestate::PipelineState,
+    rendertarget::{ColorRenderTargetKey, DepthRenderTargetKey, RenderTargetKey},
+    shader::{
+        PixelShaderKey, ShaderEntrypoints, ShaderKey, ShaderModuleDescriptor, VertexShaderKey,
+    },
+};
+
+#[cfg(not(target_family = "wasm"))]
+use super::shader_diagnostics::report_shader_error;
+
+// Remembers the shader and pipeline permutations used in previous runs, so they can be built
+// before they're needed
+#[cfg(not(target_family = "wasm"))]
+const PIPELINE_CACHE_DIR: &str = "data/cache";
+#[cfg(not(target_family = "wasm"))]
+const PIPELINE_CACHE_PATH: &str = "data/cache/pipeline_cache.json";
+
+// Everything a render pipeline is built from, render passes with the same description share a
+// pipeline. Render target formats and sample counts are looked up when the pipeline is built.
+#[derive(Clone, Hash, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct PipelineDescriptor {
+    pub vs: VertexShaderKey,
+    pub ps: PixelShaderKey,
+    pub bind_group_layouts: Vec<BindGroupLayoutKey>,
+    pub color_render_targets: Vec<ColorRenderTargetKey>,
+    pub depth_render_target: DepthRenderTargetKey,
+    // Caches written before pipeline state was configurable used the defaults
+    #[serde(default)]
+    pub pipeline_state: PipelineState,
+}
+
+// Render target state the pipeline depends on
+#[derive(Hash)]
+struct PipelineTargetState {
+    color_formats: Vec<wgpu::TextureFormat>,
+    depth_format: Option<wgpu::TextureFormat>,
+    sample_count: u32,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+struct PipelineRecord {
+    label: String,
+    descriptor: PipelineDescriptor,
+    // Content hashes of the shaders the pipeline was built with, None for the fallback shaders
+    vs_hash: Option<u64>,
+    ps_hash: Option<u64>,
+}
+
+fn pipeline_key(
+    descriptor: &PipelineDescriptor,
+    target_state: &PipelineTargetState,
+    vs_hash: Option<u64>,
+    ps_hash: Option<u64>,
+) -> u64 {
+    let mut hasher = seahash::SeaHasher::new();
+    descriptor.hash(&mut hasher);
+    target_state.hash(&mut hasher);
+    vs_hash.hash(&mut hasher);
+    ps_hash.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ShaderModuleRecord {
+    descriptor: ShaderModuleDescriptor,
+    entrypoints: Vec<(ShaderKey, String)>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PipelineCacheManifest {
+    shader_modules: Vec<ShaderModuleRecord>,
+    pipelines: Vec<PipelineRecord>,
+}
+
+struct CachedPipeline {
+    render_pipeline: Arc<wgpu::RenderPipeline>,
+    record: PipelineRecord,
+}
+
+#[derive(Default)]
+pub struct PipelineCache {
+    pipelines: HashMap<u64, CachedPipeline>,
+    manifest: PipelineCacheManifest,
+    // Pipelines recorded by a previous run, built once the shaders they need are compiled
+    pipelines_to_prewarm: Vec<PipelineRecord>,
+    manifest_changed: bool,
+}
+
+impl PipelineCache {
+    fn record_pipeline(&mut self, record: PipelineRecord) {
+        if self.manifest.pipelines.contains(&record) {
+            return;
+        }
+
+        // Only the latest version of each pipeline is worth prewarming
+        self.manifest
+            .pipelines
+            .retain(|recorded| recorded.descriptor != record.descriptor);
+        self.manifest.pipelines.push(record);
+        self.manifest_changed = true;
+    }
+
+    // Pipelines built from an older version of a shader won't be looked up again once it's
+    // reloaded
+    fn remove_stale_pipelines(&mut self, record: &PipelineRecord) {
+        self.pipelines.retain(|_, cached| {
+            let cached = &cached.record;
+            (cached.descriptor.vs != record.descriptor.vs || cached.vs_hash == record.vs_hash)
+                && (cached.descriptor.ps != record.descriptor.ps
+                    || cached.ps_hash == record.ps_hash)
+        });
+    }
+
+    fn record_shader_module(&mut self, record: ShaderModuleRecord) {
+        let permutation_hash = record.descriptor.permutation_hash();
+        let is_recorded = self.manifest.shader_modules.iter().any(|recorded| {
+            recorded.descriptor.permutation_hash() == permutation_hash
+                && recorded.entrypoints.len() == record.entrypoints.len()
+                && record
+                    .entrypoints
+                    .iter()
+                    .all(|entrypoint| recorded.entrypoints.contains(entrypoint))
+        });
+        if is_recorded {
+            return;
+        }
+
+        self.manifest
+            .shader_modules
+            .retain(|recorded| recorded.descriptor.permutation_hash() != permutation_hash);
+        self.manifest.shader_modules.push(record);
+        self.manifest_changed = true;
+    }
+}
+
+impl super::State {
+    fn pipeline_target_state(&self, descriptor: &PipelineDescriptor) -> PipelineTargetState {
+        let depth_render_target = match descriptor.depth_render_target {
+            DepthRenderTargetKey::Invalid => None,
+            key => Some(key),
+        };
+
+        PipelineTargetState {
+            color_formats: descriptor
+                .color_render_targets
+                .iter()
+                .map(|target| self.find_color_render_target_format(*target))
+                .collect(),
+            depth_format: depth_render_target.map(|key| self.find_depth_render_target_format(key)),
+            sample_count: descriptor
+                .color_render_targets
+                .first()
+                .map(|target| RenderTargetKey::Color(*target))
+                .or(depth_render_target.map(RenderTargetKey::Depth))
+                .map_or(1, |target| self.find_render_target_sample_count(target)),
+        }
+    }
+
+    fn create_pipeline(
+        &self,
+        label: &str,
+        descriptor: &PipelineDescriptor,
+        target_state: &PipelineTargetState,
+    ) -> (wgpu::RenderPipeline, PipelineRecord) {
+        let bind_group_layouts =
+            self.find_pipeline_bind_group_layouts(&descriptor.bind_group_layouts);
+        let render_pipeline_layout =
+            self.device
+                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                    label: Some(label),
+                    bind_group_layouts: &bind_group_layouts,
+                    push_constant_ranges: &[],
+                });
+
+        let (vs, ps) =
+            self.use_shaders(descriptor.vs, descriptor.ps, &descriptor.bind_group_layouts);
+
+        // Vertex shaders that failed to load use the fallback shader without any inputs
+        let vertex_attributes = self.find_vertex_attributes(descriptor.vs);
+        let vertex_buffer_layouts = [wgpu::VertexBufferLayout {
+            array_stride: self.find_vertex_shader_stride(descriptor.vs).unwrap_or(0),
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: vertex_attributes.as_deref().unwrap_or(&[]),
+        }];
+
+        let color_targets: Vec<wgpu::ColorTargetState> = target_state
+            .color_formats
+            .iter()
+            .enumerate()
+            .map(|(index, format)| wgpu::ColorTargetState {
+                format: *format,
+                blend: Some(descriptor.pipeline_state.blend_state(index)),
+                write_mask: wgpu::ColorWrites::ALL,
+            })
+            .collect();
+
+        let depth_target = target_state
+            .depth_format
+            .map(|format| descriptor.pipeline_state.depth_stencil_state(format));
+
+        let render_pipeline = self
+            .device
+            .create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&render_pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &vs.module,
+                    entry_point: &vs.entrypoint,
+                    buffers: &vertex_buffer_layouts,
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &ps.module,
+                    entry_point: &ps.entrypoint,
+                    targets: &color_targets,
+                }),
+                primitive: descriptor
+                    .pipeline_state
+                    .primitive_state(self.device.features()),
+                depth_stencil: depth_target,
+                multisample: wgpu::MultisampleState {
+                    count: target_state.sample_count,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            });
+
+        let record = PipelineRecord {
+            label: label.to_string(),
+            descriptor: descriptor.clone(),
+            vs_hash: vs.content_hash,
+            ps_hash: ps.content_hash,
+        };
+
+        (render_pipeline, record)
+    }
+
+    fn find_or_create_unrecorded_pipeline(
+        &self,
+        label: &str,
+        descriptor: &PipelineDescriptor,
+    ) -> (Arc<wgpu::RenderPipeline>, PipelineRecord) {
+        let target_state = self.pipeline_target_state(descriptor);
+        let key = {
+            let (vs, ps) =
+                self.use_shaders(descriptor.vs, descriptor.ps, &descriptor.bind_group_layouts);
+            pipeline_key(descriptor, &target_state, vs.content_hash, ps.content_hash)
+        };
+
+        if let Some(cached) = self.pipeline_cache.read().unwrap().pipelines.get(&key) {
+            return (cached.render_pipeline.clone(), cached.record.clone());
+        }
+
+        let (render_pipeline, record) = self.create_pipeline(label, descriptor, &target_state);
+        let render_pipeline = Arc::new(render_pipeline);
+
+        let mut pipeline_cache = self.pipeline_cache.write().unwrap();
+        pipeline_cache.remove_stale_pipelines(&record);
+        pipeline_cache.pipelines.insert(
+            key,
+            CachedPipeline {
+                render_pipeline: render_pipeline.clone(),
+                record: record.clone(),
+            },
+        );
+
+        (render_pipeline, record)
+    }
+
+    // Records the pipeline and its shader modules for the next run to prewarm
+    fn record_pipeline(&self, record: PipelineRecord) {
+        // Fallback pipelines are only used until the real shaders are ready, and user-defined
+        // render targets get different keys every run
+        let has_user_render_targets = record
+            .descriptor
+            .color_render_targets
+            .iter()
+            .any(|target| matches!(target, ColorRenderTargetKey::Other(_)))
+            || matches!(
+                record.descriptor.depth_render_target,
+                DepthRenderTargetKey::Other(_)
+            );
+        if record.vs_hash.is_none() || record.ps_hash.is_none() || has_user_render_targets {
+            return;
+        }
+
+        let shader_modules: Vec<ShaderModuleRecord> = [
+            ShaderKey::VS(record.descriptor.vs),
+            ShaderKey::PS(record.descriptor.ps),
+        ]
+        .into_iter()
+        .filter_map(|shader_key| self.find_shader_module_entrypoints(shader_key))
+        .map(|(descriptor, entrypoints)| ShaderModuleRecord {
+            descriptor,
+            entrypoints,
+        })
+        .collect();
+
+        let mut pipeline_cache = self.pipeline_cache.write().unwrap();
+        for shader_module in shader_modules {
+            pipeline_cache.record_shader_module(shader_module);
+        }
+        pipeline_cache.record_pipeline(record);
+    }
+
+    // Pipelines are keyed by their description and the shaders they're built from, so a reloaded
+    // shader only rebuilds the pipelines that use it. Only pipelines that are used get recorded,
+    // prewarmed ones that aren't drop out of the cache.
+    pub fn find_or_create_pipeline(
+        &self,
+        label: &str,
+        descriptor: &PipelineDescriptor,
+    ) -> Arc<wgpu::RenderPipeline> {
+        let (render_pipeline, record) = self.find_or_create_unrecorded_pipeline(label, descriptor);
+        self.record_pipeline(record);
+        render_pipeline
+    }
+
+    // Loads the permutations recorded by previous runs and starts compiling their shaders. Only
+    // what render passes still use gets recorded again, so stale permutations drop out of the
+    // cache.
+    #[cfg(not(target_family = "wasm"))]
+    pub async fn prewarm_shader_modules(&mut self) {
+        let manifest: PipelineCacheManifest = match std::fs::read(PIPELINE_CACHE_PATH) {
+            Ok(buf) => match serde_json::from_slice(&buf) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    // An outdated cache is rebuilt from scratch
+                    report_shader_error(&format!(
+                        "Ignoring pipeline cache {}: {}",
+                        PIPELINE_CACHE_PATH, e
+                    ));
+                    return;
+                }
+            },
+            Err(_) => return,
+        };
+
+        for record in manifest.shader_modules {
+            if self.is_shader_module_loaded(&record.descriptor) {
+                continue;
+            }
+
+            let entrypoints: Vec<ShaderEntrypoints> = record
+                .entrypoints
+                .iter()
+                .map(|(key, entrypoint)| match key {
+                    ShaderKey::VS(key) => ShaderEntrypoints::VS((*key, entrypoint.as_str())),
+                    ShaderKey::PS(key) => ShaderEntrypoints::PS((*key, entrypoint.as_str())),
+                })
+                .collect();
+
+            // Shaders that were removed or broken since are dropped from the cache
+            if let Err(e) = self
+                .add_shader_module(record.descriptor.clone(), &entrypoints)
+                .await
+            {
+                report_shader_error(&format!(
+                    "Failed to prewarm shader {}: {}",
+                    record.descriptor.path, e
+                ));
+            }
+        }
+
+        let mut pipeline_cache = self.pipeline_cache.write().unwrap();
+        pipeline_cache.pipelines_to_prewarm = manifest.pipelines;
+    }
+
+    // Builds the recorded pipelines whose shaders haven't changed, called once shader processing
+    // is done
+    pub fn prewarm_pipelines(&self) {
+        let pipelines_to_prewarm =
+            std::mem::take(&mut self.pipeline_cache.write().unwrap().pipelines_to_prewarm);
+
+        for record in pipelines_to_prewarm {
+            let (vs, ps) = self.use_shaders(
+                record.descriptor.vs,
+                record.descriptor.ps,
+                &record.descriptor.bind_group_layouts,
+            );
+            let up_to_date = vs.content_hash == record.vs_hash && ps.content_hash == record.ps_hash;
+            drop((vs, ps));
+
+            // Render targets that don't exist yet can't be prewarmed for
+            let has_render_targets = record
+                .descriptor
+                .color_render_targets
+                .iter()
+                .map(|target| RenderTargetKey::Color(*target))
+                .chain(match record.descriptor.depth_render_target {
+                    DepthRenderTargetKey::Invalid => None,
+                    key => Some(RenderTargetKey::Depth(key)),
+                })
+                .all(|target| self.has_render_target(target));
+
+            if up_to_date && has_render_targets {
+                self.find_or_create_unrecorded_pipeline(&record.label, &record.descriptor);
+            }
+        }
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    pub fn save_pipeline_cache(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let mut pipeline_cache = self.pipeline_cache.write().unwrap();
+        if !pipeline_cache.manifest_changed {
+            return Ok(());
+        }
+        // A failed save isn't retried every frame, only once the manifest changes again
+        pipeline_cache.manifest_changed = false;
+
+        std::fs::create_dir_all(PIPELINE_CACHE_DIR)?;
+        std::fs::write(
+            PIPELINE_CACHE_PATH,
+            serde_json::to_vec_pretty(&pipeline_cache.manifest)?,
+        )
+        .map_err(|_| format!("Failed to write file {}", PIPELINE_CACHE_PATH))?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,403 @@
+use std::hash::{Hash, Hasher};
+
+use serde::{Deserialize, Serialize};
+
+// Fixed function state of a render pipeline. These mirror the wgpu types so they can be hashed
+// and written to the pipeline cache.
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum BlendMode {
+    #[default]
+    Replace,
+    Alpha,
+    PremultipliedAlpha,
+    Additive,
+    Multiply,
+}
+
+impl BlendMode {
+    fn blend_state(self) -> wgpu::BlendState {
+        match self {
+            BlendMode::Replace => wgpu::BlendState::REPLACE,
+            BlendMode::Alpha => wgpu::BlendState::ALPHA_BLENDING,
+            BlendMode::PremultipliedAlpha => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+            BlendMode::Additive => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::One,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+            // Alpha is left as it is, so decals can darken without punching holes
+            BlendMode::Multiply => wgpu::BlendState {
+                color: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Dst,
+                    dst_factor: wgpu::BlendFactor::Zero,
+                    operation: wgpu::BlendOperation::Add,
+                },
+                alpha: wgpu::BlendComponent {
+                    src_factor: wgpu::BlendFactor::Zero,
+                    dst_factor: wgpu::BlendFactor::One,
+                    operation: wgpu::BlendOperation::Add,
+                },
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum CullMode {
+    None,
+    Front,
+    #[default]
+    Back,
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum FrontFace {
+    #[default]
+    Ccw,
+    Cw,
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum PrimitiveTopology {
+    PointList,
+    LineList,
+    LineStrip,
+    #[default]
+    TriangleList,
+    TriangleStrip,
+}
+
+impl PrimitiveTopology {
+    pub fn is_strip(self) -> bool {
+        matches!(
+            self,
+            PrimitiveTopology::LineStrip | PrimitiveTopology::TriangleStrip
+        )
+    }
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum IndexFormat {
+    Uint16,
+    #[default]
+    Uint32,
+}
+
+impl IndexFormat {
+    pub fn index_format(self) -> wgpu::IndexFormat {
+        match self {
+            IndexFormat::Uint16 => wgpu::IndexFormat::Uint16,
+            IndexFormat::Uint32 => wgpu::IndexFormat::Uint32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum PolygonMode {
+    #[default]
+    Fill,
+    Line,
+    Point,
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum CompareFunction {
+    Never,
+    #[default]
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+impl CompareFunction {
+    fn compare_function(self) -> wgpu::CompareFunction {
+        match self {
+            CompareFunction::Never => wgpu::CompareFunction::Never,
+            CompareFunction::Less => wgpu::CompareFunction::Less,
+            CompareFunction::Equal => wgpu::CompareFunction::Equal,
+            CompareFunction::LessEqual => wgpu::CompareFunction::LessEqual,
+            CompareFunction::Greater => wgpu::CompareFunction::Greater,
+            CompareFunction::NotEqual => wgpu::CompareFunction::NotEqual,
+            CompareFunction::GreaterEqual => wgpu::CompareFunction::GreaterEqual,
+            CompareFunction::Always => wgpu::CompareFunction::Always,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub enum StencilOperation {
+    #[default]
+    Keep,
+    Zero,
+    Replace,
+    Invert,
+    IncrementClamp,
+    DecrementClamp,
+    IncrementWrap,
+    DecrementWrap,
+}
+
+impl StencilOperation {
+    fn stencil_operation(self) -> wgpu::StencilOperation {
+        match self {
+            StencilOperation::Keep => wgpu::StencilOperation::Keep,
+            StencilOperation::Zero => wgpu::StencilOperation::Zero,
+            StencilOperation::Replace => wgpu::StencilOperation::Replace,
+            StencilOperation::Invert => wgpu::StencilOperation::Invert,
+            StencilOperation::IncrementClamp => wgpu::StencilOperation::IncrementClamp,
+            StencilOperation::DecrementClamp => wgpu::StencilOperation::DecrementClamp,
+            StencilOperation::IncrementWrap => wgpu::StencilOperation::IncrementWrap,
+            StencilOperation::DecrementWrap => wgpu::StencilOperation::DecrementWrap,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct StencilFaceState {
+    pub compare: CompareFunction,
+    pub fail_op: StencilOperation,
+    pub depth_fail_op: StencilOperation,
+    pub pass_op: StencilOperation,
+}
+
+impl Default for StencilFaceState {
+    fn default() -> Self {
+        Self {
+            compare: CompareFunction::Always,
+            fail_op: StencilOperation::Keep,
+            depth_fail_op: StencilOperation::Keep,
+            pass_op: StencilOperation::Keep,
+        }
+    }
+}
+
+impl StencilFaceState {
+    fn stencil_face_state(&self) -> wgpu::StencilFaceState {
+        wgpu::StencilFaceState {
+            compare: self.compare.compare_function(),
+            fail_op: self.fail_op.stencil_operation(),
+            depth_fail_op: self.depth_fail_op.stencil_operation(),
+            pass_op: self.pass_op.stencil_operation(),
+        }
+    }
+}
+
+// Only used with depth render targets that have a stencil aspect
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub struct StencilState {
+    pub front: StencilFaceState,
+    pub back: StencilFaceState,
+    pub read_mask: u32,
+    pub write_mask: u32,
+}
+
+impl StencilState {
+    pub fn is_enabled(&self) -> bool {
+        self.stencil_state().is_enabled()
+    }
+
+    fn stencil_state(&self) -> wgpu::StencilState {
+        wgpu::StencilState {
+            front: self.front.stencil_face_state(),
+            back: self.back.stencil_face_state(),
+            read_mask: self.read_mask,
+            write_mask: self.write_mask,
+        }
+    }
+}
+
+// Shadow casters push their depth away from the surfaces they shadow
+#[derive(Copy, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct DepthBias {
+    pub constant: i32,
+    pub slope_scale: f32,
+    pub clamp: f32,
+}
+
+impl DepthBias {
+    fn bits(&self) -> (i32, u32, u32) {
+        (
+            self.constant,
+            self.slope_scale.to_bits(),
+            self.clamp.to_bits(),
+        )
+    }
+}
+
+impl Hash for DepthBias {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.bits().hash(state);
+    }
+}
+
+impl PartialEq for DepthBias {
+    fn eq(&self, other: &Self) -> bool {
+        self.bits() == other.bits()
+    }
+}
+
+impl Eq for DepthBias {}
+
+#[derive(Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
+pub struct PipelineState {
+    // One per color render target, targets without an entry use BlendMode::Replace
+    pub blend_modes: Vec<BlendMode>,
+    pub cull_mode: CullMode,
+    pub front_face: FrontFace,
+    pub topology: PrimitiveTopology,
+    // Format of the index buffers strips are drawn from, unused by other topologies
+    #[serde(default)]
+    pub strip_index_format: IndexFormat,
+    pub polygon_mode: PolygonMode,
+    pub depth_write_enabled: bool,
+    pub depth_compare: CompareFunction,
+    pub stencil: StencilState,
+    pub depth_bias: DepthBias,
+}
+
+impl Default for PipelineState {
+    fn default() -> Self {
+        Self {
+            blend_modes: Vec::new(),
+            cull_mode: CullMode::Back,
+            front_face: FrontFace::Ccw,
+            topology: PrimitiveTopology::TriangleList,
+            strip_index_format: IndexFormat::Uint32,
+            polygon_mode: PolygonMode::Fill,
+            depth_write_enabled: true,
+            depth_compare: CompareFunction::Less,
+            stencil: StencilState::default(),
+            depth_bias: DepthBias::default(),
+        }
+    }
+}
+
+// What a draw changes about its render pass's pipeline state. Passes build a pipeline for each
+// variant they draw, see `RenderPass::pipeline_variants`.
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+pub struct PipelineVariant {
+    pub topology: PrimitiveTopology,
+    pub strip_index_format: IndexFormat,
+    // None keeps the pass's blend mode for the first color render target
+    pub blend_mode: Option<BlendMode>,
+    // None keeps the pass's cull mode
+    pub cull_mode: Option<CullMode>,
+    // None keeps the pass's depth writes
+    pub depth_write_enabled: Option<bool>,
+}
+
+impl PipelineVariant {
+    // Strips are the only topologies that care about the index format
+    pub fn matches(&self, other: &PipelineVariant) -> bool {
+        self.topology == other.topology
+            && (!self.topology.is_strip() || self.strip_index_format == other.strip_index_format)
+            && self.blend_mode == other.blend_mode
+            && self.cull_mode == other.cull_mode
+            && self.depth_write_enabled == other.depth_write_enabled
+    }
+
+    pub fn apply(&self, pipeline_state: &PipelineState) -> PipelineState {
+        let mut variant_state = pipeline_state.clone();
+        variant_state.topology = self.topology;
+        variant_state.strip_index_format = self.strip_index_format;
+        if let Some(blend_mode) = self.blend_mode {
+            match variant_state.blend_modes.first_mut() {
+                Some(first_blend_mode) => *first_blend_mode = blend_mode,
+                None => variant_state.blend_modes.push(blend_mode),
+            }
+        }
+        if let Some(cull_mode) = self.cull_mode {
+            variant_state.cull_mode = cull_mode;
+        }
+        if let Some(depth_write_enabled) = self.depth_write_enabled {
+            variant_state.depth_write_enabled = depth_write_enabled;
+        }
+        variant_state
+    }
+}
+
+impl PipelineState {
+    // The variant drawing with the pipeline state as it is
+    pub fn base_variant(&self) -> PipelineVariant {
+        PipelineVariant {
+            topology: self.topology,
+            strip_index_format: self.strip_index_format,
+            blend_mode: None,
+            cull_mode: None,
+            depth_write_enabled: None,
+        }
+    }
+
+    pub fn blend_state(&self, color_target_index: usize) -> wgpu::BlendState {
+        self.blend_modes
+            .get(color_target_index)
+            .copied()
+            .unwrap_or_default()
+            .blend_state()
+    }
+
+    pub fn primitive_state(&self, features: wgpu::Features) -> wgpu::PrimitiveState {
+        // Line and point rasterization are optional, meshes are drawn filled without them
+        let polygon_mode = match self.polygon_mode {
+            PolygonMode::Line if features.contains(wgpu::Features::POLYGON_MODE_LINE) => {
+                wgpu::PolygonMode::Line
+            }
+            PolygonMode::Point if features.contains(wgpu::Features::POLYGON_MODE_POINT) => {
+                wgpu::PolygonMode::Point
+            }
+            _ => wgpu::PolygonMode::Fill,
+        };
+
+        wgpu::PrimitiveState {
+            topology: match self.topology {
+                PrimitiveTopology::PointList => wgpu::PrimitiveTopology::PointList,
+                PrimitiveTopology::LineList => wgpu::PrimitiveTopology::LineList,
+                PrimitiveTopology::LineStrip => wgpu::PrimitiveTopology::LineStrip,
+                PrimitiveTopology::TriangleList => wgpu::PrimitiveTopology::TriangleList,
+                PrimitiveTopology::TriangleStrip => wgpu::PrimitiveTopology::TriangleStrip,
+            },
+            strip_index_format: self
+                .topology
+                .is_strip()
+                .then_some(self.strip_index_format.index_format()),
+            front_face: match self.front_face {
+                FrontFace::Ccw => wgpu::FrontFace::Ccw,
+                FrontFace::Cw => wgpu::FrontFace::Cw,
+            },
+            cull_mode: match self.cull_mode {
+                CullMode::None => None,
+                CullMode::Front => Some(wgpu::Face::Front),
+                CullMode::Back => Some(wgpu::Face::Back),
+            },
+            polygon_mode,
+            unclipped_depth: false,
+            conservative: false,
+        }
+    }
+
+    pub fn depth_stencil_state(&self, format: wgpu::TextureFormat) -> wgpu::DepthStencilState {
+        wgpu::DepthStencilState {
+            format,
+            depth_write_enabled: self.depth_write_enabled,
+            depth_compare: self.depth_compare.compare_function(),
+            stencil: self.stencil.stencil_state(),
+            bias: wgpu::DepthBiasState {
+                constant: self.depth_bias.constant,
+                slope_scale: self.depth_bias.slope_scale,
+                clamp: self.depth_bias.clamp,
+            },
+        }
+    }
+}
@@ -1,20 +1,83 @@
-use std::ops::Deref;
-
-use wgpu::DepthStencilState;
+use std::{
+    ops::{Deref, DerefMut},
+    sync::Arc,
+};
 
 use super::{
+    bindgroup::BindGroupLayoutKey,
+    pipeline::PipelineDescriptor,
+    pipelinestate::{PipelineState, PipelineVariant},
     rendertarget::{ColorRenderTargetKey, DepthRenderTargetKey},
-    shader::{PixelShaderKey, ShaderKey, VertexShaderKey},
+    shader::{PixelShaderKey, VertexShaderKey},
 };
 
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AttachmentLoadOp<V> {
+    Clear(V),
+    Load,
+    // The previous contents aren't needed, the pass covers every pixel it uses
+    DontCare,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AttachmentOps<V> {
+    pub load: AttachmentLoadOp<V>,
+    pub store: bool,
+}
+
+impl<V> AttachmentOps<V> {
+    pub fn clear(value: V) -> Self {
+        Self {
+            load: AttachmentLoadOp::Clear(value),
+            store: true,
+        }
+    }
+
+    pub fn load() -> Self {
+        Self {
+            load: AttachmentLoadOp::Load,
+            store: true,
+        }
+    }
+
+    // wgpu has no way to leave an attachment undefined, clearing is the next cheapest thing
+    fn operations(self, dont_care_value: V) -> wgpu::Operations<V> {
+        wgpu::Operations {
+            load: match self.load {
+                AttachmentLoadOp::Clear(value) => wgpu::LoadOp::Clear(value),
+                AttachmentLoadOp::Load => wgpu::LoadOp::Load,
+                AttachmentLoadOp::DontCare => wgpu::LoadOp::Clear(dont_care_value),
+            },
+            store: self.store,
+        }
+    }
+}
+
 pub struct RenderPass {
     pub name: &'static str,
     pub vs: VertexShaderKey,
     pub ps: PixelShaderKey,
     pub color_render_targets: Vec<ColorRenderTargetKey>,
     pub depth_render_target: DepthRenderTargetKey,
+    pub bind_group_layouts: Vec<BindGroupLayoutKey>,
+    // One per color render target, targets without an entry are cleared to transparent black
+    pub color_ops: Vec<AttachmentOps<wgpu::Color>>,
+    pub depth_ops: AttachmentOps<f32>,
+    // None keeps the stencil read only, passes writing stencil values need it set
+    pub stencil_ops: Option<AttachmentOps<u32>>,
+    pub pipeline_state: PipelineState,
+    pub stencil_reference: u32,
+    // Variants drawn besides `pipeline_state` itself, like other topologies or the blend and cull
+    // modes of materials. Each gets its own pipeline, draws pick one with
+    // `RenderPassEncoder::set_pipeline_variant`.
+    pub pipeline_variants: Vec<PipelineVariant>,
 
-    render_pipeline: Option<wgpu::RenderPipeline>,
+    render_pipeline: Option<Arc<wgpu::RenderPipeline>>,
+    variant_render_pipelines: Vec<(PipelineVariant, Arc<wgpu::RenderPipeline>)>,
+    pipeline_descriptor: Option<PipelineDescriptor>,
+    pipeline_descriptor_variants: Vec<PipelineVariant>,
+    pipeline_shader_generation: u64,
+    pipeline_render_target_generation: u64,
 }
 
 impl RenderPass {
@@ -25,75 +88,64 @@ impl RenderPass {
             ps: PixelShaderKey::Invalid,
             color_render_targets: Vec::new(),
             depth_render_target: DepthRenderTargetKey::Invalid,
+            bind_group_layouts: Vec::new(),
+            color_ops: Vec::new(),
+            depth_ops: AttachmentOps::clear(1.0),
+            stencil_ops: None,
+            pipeline_state: PipelineState::default(),
+            stencil_reference: 0,
+            pipeline_variants: Vec::new(),
             render_pipeline: None,
+            variant_render_pipelines: Vec::new(),
+            pipeline_descriptor: None,
+            pipeline_descriptor_variants: Vec::new(),
+            pipeline_shader_generation: 0,
+            pipeline_render_target_generation: 0,
+        }
+    }
+
+    fn pipeline_descriptor(&self) -> PipelineDescriptor {
+        PipelineDescriptor {
+            vs: self.vs,
+            ps: self.ps,
+            bind_group_layouts: self.bind_group_layouts.clone(),
+            color_render_targets: self.color_render_targets.clone(),
+            depth_render_target: self.depth_render_target,
+            pipeline_state: self.pipeline_state.clone(),
         }
     }
 
-    fn rebuild_pipeline(&mut self, state: &super::State) {
-        let render_pipeline_layout =
-            state
-                .device
-                .create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    label: Some(&self.name),
-                    bind_group_layouts: &[],
-                    push_constant_ranges: &[],
-                });
-
-        let vs = state.use_shader(ShaderKey::VS(self.vs));
-        let ps = state.use_shader(ShaderKey::PS(self.ps));
-
-        let mut color_targets = Vec::with_capacity(self.color_render_targets.len());
-        for target in &self.color_render_targets {
-            color_targets.push(wgpu::ColorTargetState {
-                format: state.find_color_render_target_format(*target),
-                blend: Some(wgpu::BlendState::REPLACE),
-                write_mask: wgpu::ColorWrites::ALL,
+    fn rebuild_pipeline(&mut self, state: &super::State, descriptor: PipelineDescriptor) {
+        self.pipeline_shader_generation = state.shader_generation();
+        self.pipeline_render_target_generation = state.render_target_generation();
+        self.render_pipeline = Some(state.find_or_create_pipeline(self.name, &descriptor));
+
+        self.variant_render_pipelines = self
+            .pipeline_variants
+            .iter()
+            .map(|variant| {
+                let mut variant_descriptor = descriptor.clone();
+                variant_descriptor.pipeline_state = variant.apply(&descriptor.pipeline_state);
+                (
+                    *variant,
+                    state.find_or_create_pipeline(self.name, &variant_descriptor),
+                )
             })
+            .collect();
+
+        self.pipeline_descriptor = Some(descriptor);
+        self.pipeline_descriptor_variants = self.pipeline_variants.clone();
+    }
+
+    fn find_variant_pipeline(&self, variant: &PipelineVariant) -> Option<&wgpu::RenderPipeline> {
+        if variant.matches(&self.pipeline_state.base_variant()) {
+            return self.render_pipeline.as_deref();
         }
 
-        let depth_target = match self.depth_render_target {
-            DepthRenderTargetKey::Invalid => None,
-            key => Some(DepthStencilState {
-                format: state.find_depth_render_target_format(key),
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-        };
-
-        self.render_pipeline = Some(state.device.create_render_pipeline(
-            &wgpu::RenderPipelineDescriptor {
-                label: Some(&self.name),
-                layout: Some(&render_pipeline_layout),
-                vertex: wgpu::VertexState {
-                    module: &vs.module,
-                    entry_point: &vs.entrypoint,
-                    buffers: state.find_vertex_buffer_layout(self.vs).deref(),
-                },
-                fragment: Some(wgpu::FragmentState {
-                    module: &ps.module,
-                    entry_point: &ps.entrypoint,
-                    targets: &color_targets,
-                }),
-                primitive: wgpu::PrimitiveState {
-                    topology: wgpu::PrimitiveTopology::TriangleList,
-                    strip_index_format: None,
-                    front_face: wgpu::FrontFace::Ccw,
-                    cull_mode: Some(wgpu::Face::Back),
-                    polygon_mode: wgpu::PolygonMode::Fill,
-                    unclipped_depth: false,
-                    conservative: false,
-                },
-                depth_stencil: depth_target,
-                multisample: wgpu::MultisampleState {
-                    count: 1,
-                    mask: !0,
-                    alpha_to_coverage_enabled: false,
-                },
-                multiview: None,
-            },
-        ));
+        self.variant_render_pipelines
+            .iter()
+            .find(|(pipeline_variant, _)| variant.matches(pipeline_variant))
+            .map(|(_, pipeline)| pipeline.as_ref())
     }
 
     pub fn begin_frame<'a>(
@@ -101,19 +153,88 @@ impl RenderPass {
         frame_state: &'a mut RenderPassFrameState<'a>,
         state: &'a super::State,
         encoder: &'a mut wgpu::CommandEncoder,
-    ) -> wgpu::RenderPass<'a> {
-        if self.render_pipeline.is_none() {
-            self.rebuild_pipeline(&state);
+    ) -> RenderPassEncoder<'a> {
+        // Changing any of the pass's pipeline state picks a different pipeline
+        let descriptor = self.pipeline_descriptor();
+        if self.render_pipeline.is_none()
+            || self.pipeline_shader_generation != state.shader_generation()
+            || self.pipeline_render_target_generation != state.render_target_generation()
+            || self.pipeline_descriptor.as_ref() != Some(&descriptor)
+            || self.pipeline_descriptor_variants != self.pipeline_variants
+        {
+            self.rebuild_pipeline(&state, descriptor);
+        }
+
+        let render_pass_descriptor: &'a RenderPass = self;
+        let mut render_pass = frame_state.build(render_pass_descriptor, state, encoder);
+        render_pass.set_pipeline(render_pass_descriptor.render_pipeline.as_ref().unwrap());
+        if render_pass_descriptor.pipeline_state.stencil.is_enabled() {
+            render_pass.set_stencil_reference(render_pass_descriptor.stencil_reference);
+        }
+
+        // Model and material bind groups are set by whatever is drawn
+        if render_pass_descriptor
+            .bind_group_layouts
+            .contains(&BindGroupLayoutKey::World)
+        {
+            render_pass.set_bind_group(
+                BindGroupLayoutKey::World.group_index(),
+                state.world_bind_group(),
+                &[],
+            );
+        }
+
+        RenderPassEncoder {
+            render_pass,
+            render_pass_descriptor,
+            variant: render_pass_descriptor.pipeline_state.base_variant(),
+        }
+    }
+}
+
+// A render pass being recorded, derefs to the wgpu pass
+pub struct RenderPassEncoder<'a> {
+    render_pass: wgpu::RenderPass<'a>,
+    render_pass_descriptor: &'a RenderPass,
+    variant: PipelineVariant,
+}
+
+impl<'a> RenderPassEncoder<'a> {
+    // Switches to the pipeline for `variant`, returns false if the pass has none, see
+    // `RenderPass::pipeline_variants`
+    pub fn set_pipeline_variant(&mut self, variant: PipelineVariant) -> bool {
+        if self.variant.matches(&variant) {
+            return true;
         }
 
-        let mut render_pass = frame_state.build(self, state, encoder);
-        render_pass.set_pipeline(&self.render_pipeline.as_ref().unwrap());
-        render_pass
+        match self.render_pass_descriptor.find_variant_pipeline(&variant) {
+            Some(pipeline) => {
+                self.render_pass.set_pipeline(pipeline);
+                self.variant = variant;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl<'a> Deref for RenderPassEncoder<'a> {
+    type Target = wgpu::RenderPass<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.render_pass
+    }
+}
+
+impl<'a> DerefMut for RenderPassEncoder<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.render_pass
     }
 }
 
 pub struct RenderPassFrameState<'a> {
     color_texture_views: Vec<wgpu::TextureView>,
+    color_resolve_texture_views: Vec<Option<wgpu::TextureView>>,
     color_attachments: Vec<wgpu::RenderPassColorAttachment<'a>>,
     depth_texture_view: Option<wgpu::TextureView>,
 }
@@ -122,6 +243,7 @@ impl<'a> RenderPassFrameState<'a> {
     pub fn new() -> Self {
         RenderPassFrameState {
             color_texture_views: Vec::new(),
+            color_resolve_texture_views: Vec::new(),
             color_attachments: Vec::new(),
             depth_texture_view: None,
         }
@@ -133,20 +255,29 @@ impl<'a> RenderPassFrameState<'a> {
         state: &super::State,
         encoder: &'a mut wgpu::CommandEncoder,
     ) -> wgpu::RenderPass {
-        self.color_texture_views = render_pass
+        (self.color_texture_views, self.color_resolve_texture_views) = render_pass
             .color_render_targets
             .iter()
-            .map(|target| state.find_color_render_target(*target))
-            .collect();
+            .map(|target| state.find_color_render_target_attachment(*target))
+            .unzip();
 
         self.color_attachments = self
             .color_texture_views
             .iter()
-            .map(|texture_view| wgpu::RenderPassColorAttachment {
-                view: &texture_view,
-                resolve_target: None,
-                ops: wgpu::Operations::default(),
-            })
+            .zip(self.color_resolve_texture_views.iter())
+            .enumerate()
+            .map(
+                |(index, (texture_view, resolve_texture_view))| wgpu::RenderPassColorAttachment {
+                    view: &texture_view,
+                    resolve_target: resolve_texture_view.as_ref(),
+                    ops: render_pass
+                        .color_ops
+                        .get(index)
+                        .copied()
+                        .unwrap_or(AttachmentOps::clear(wgpu::Color::TRANSPARENT))
+                        .operations(wgpu::Color::TRANSPARENT),
+                },
+            )
             .collect();
 
         if render_pass.depth_render_target != DepthRenderTargetKey::Invalid {
@@ -160,11 +291,10 @@ impl<'a> RenderPassFrameState<'a> {
             depth_stencil_attachment: self.depth_texture_view.as_ref().map(|depth_texture_view| {
                 wgpu::RenderPassDepthStencilAttachment {
                     view: &depth_texture_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: true,
-                    }),
-                    stencil_ops: None,
+                    depth_ops: Some(render_pass.depth_ops.operations(1.0)),
+                    stencil_ops: render_pass
+                        .stencil_ops
+                        .map(|stencil_ops| stencil_ops.operations(0)),
                 }
             }),
         })
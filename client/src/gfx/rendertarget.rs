@@ -1,4 +1,8 @@
-#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
 pub enum ColorRenderTargetKey {
     #[default]
     Invalid,
@@ -6,7 +10,7 @@ pub enum ColorRenderTargetKey {
     Other(u32),
 }
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
 pub enum DepthRenderTargetKey {
     #[default]
     Invalid,
@@ -20,48 +24,509 @@ pub enum RenderTargetKey {
     Depth(DepthRenderTargetKey),
 }
 
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RenderTargetSize {
+    Absolute { width: u32, height: u32 },
+    WindowRelative { scale: f32 },
+}
+
+impl RenderTargetSize {
+    pub fn resolve(&self, window_size: winit::dpi::PhysicalSize<u32>) -> (u32, u32) {
+        match *self {
+            RenderTargetSize::Absolute { width, height } => (width, height),
+            RenderTargetSize::WindowRelative { scale } => (
+                ((window_size.width as f32 * scale) as u32).max(1),
+                ((window_size.height as f32 * scale) as u32).max(1),
+            ),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum RenderTargetSampleCount {
+    Absolute(u32),
+    // Follows the window's sample count, see State::set_sample_count
+    Window,
+}
+
+impl RenderTargetSampleCount {
+    pub fn resolve(&self, window_sample_count: u32) -> u32 {
+        match *self {
+            RenderTargetSampleCount::Absolute(sample_count) => sample_count,
+            RenderTargetSampleCount::Window => window_sample_count,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RenderTargetDescriptor {
+    pub name: String,
+    pub format: wgpu::TextureFormat,
+    pub size: RenderTargetSize,
+    pub sample_count: RenderTargetSampleCount,
+    // RENDER_ATTACHMENT is always added. Multisampled color targets apply this to the texture
+    // they resolve into.
+    pub usage: wgpu::TextureUsages,
+}
+
+struct RenderTarget {
+    descriptor: RenderTargetDescriptor,
+    texture: wgpu::Texture,
+    // Multisampled color targets are resolved into this at the end of each pass
+    resolve_texture: Option<wgpu::Texture>,
+    width: u32,
+    height: u32,
+    sample_count: u32,
+}
+
+fn create_render_target_texture(
+    device: &wgpu::Device,
+    label: &str,
+    (width, height): (u32, u32),
+    sample_count: u32,
+    format: wgpu::TextureFormat,
+    usage: wgpu::TextureUsages,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage,
+    })
+}
+
+impl RenderTarget {
+    fn new(
+        device: &wgpu::Device,
+        window_size: winit::dpi::PhysicalSize<u32>,
+        window_sample_count: u32,
+        descriptor: RenderTargetDescriptor,
+    ) -> Self {
+        let (width, height) = descriptor.size.resolve(window_size);
+        let sample_count = descriptor.sample_count.resolve(window_sample_count);
+        let usage = descriptor.usage | wgpu::TextureUsages::RENDER_ATTACHMENT;
+
+        // Depth can't be resolved, multisampled depth targets are used as they are
+        let resolve = sample_count > 1 && !RenderTargetCache::is_depth_format(descriptor.format);
+
+        let texture = create_render_target_texture(
+            device,
+            &descriptor.name,
+            (width, height),
+            sample_count,
+            descriptor.format,
+            if resolve {
+                wgpu::TextureUsages::RENDER_ATTACHMENT
+            } else {
+                usage
+            },
+        );
+
+        let resolve_texture = resolve.then(|| {
+            create_render_target_texture(
+                device,
+                &format!("{} resolve", descriptor.name),
+                (width, height),
+                1,
+                descriptor.format,
+                usage,
+            )
+        });
+
+        Self {
+            descriptor,
+            texture,
+            resolve_texture,
+            width,
+            height,
+            sample_count,
+        }
+    }
+
+    fn needs_recreate(
+        &self,
+        window_size: winit::dpi::PhysicalSize<u32>,
+        window_sample_count: u32,
+    ) -> bool {
+        let size_changed = matches!(
+            self.descriptor.size,
+            RenderTargetSize::WindowRelative { .. }
+        ) && self.descriptor.size.resolve(window_size)
+            != (self.width, self.height);
+
+        size_changed
+            || self.descriptor.sample_count.resolve(window_sample_count) != self.sample_count
+    }
+}
+
 pub struct RenderTargetCache {
     depth_buffer_texture: Option<wgpu::Texture>,
+    offscreen_window_texture: Option<wgpu::Texture>,
+    // Drawn to instead of the window texture when multisampling, then resolved into it
+    multisampled_window_texture: Option<wgpu::Texture>,
+    sample_count: u32,
+    // Bumped when render targets are recreated or their formats or sample counts change, so
+    // render passes rebuild what they built from them
+    render_target_generation: u64,
+    render_target_counter: u32,
+    color_render_targets: HashMap<u32, RenderTarget>,
+    depth_render_targets: HashMap<u32, RenderTarget>,
 }
 
 impl RenderTargetCache {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float;
+
+    fn is_depth_format(format: wgpu::TextureFormat) -> bool {
+        format.describe().sample_type == wgpu::TextureSampleType::Depth
+    }
+
+    fn validate_sample_count(
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let required_features = if Self::is_depth_format(format) {
+            wgpu::TextureFormatFeatureFlags::MULTISAMPLE
+        } else {
+            wgpu::TextureFormatFeatureFlags::MULTISAMPLE
+                | wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE
+        };
+
+        // Only 1 and 4 samples are supported everywhere
+        if !matches!(sample_count, 1 | 4) {
+            Err(format!("Invalid sample count {}", sample_count))?
+        }
+        if sample_count > 1
+            && !format
+                .describe()
+                .guaranteed_format_features
+                .flags
+                .contains(required_features)
+        {
+            Err(format!("Format {:?} doesn't support multisampling", format))?
+        }
+
+        Ok(())
+    }
+
+    fn next_render_target_id(&mut self) -> u32 {
+        self.render_target_counter += 1;
+        self.render_target_counter
+    }
+
+    pub fn add_color_render_target(
+        &mut self,
+        device: &wgpu::Device,
+        window_size: winit::dpi::PhysicalSize<u32>,
+        descriptor: RenderTargetDescriptor,
+    ) -> Result<ColorRenderTargetKey, Box<dyn std::error::Error>> {
+        if Self::is_depth_format(descriptor.format) {
+            Err(format!(
+                "Color render target {} can't use depth format {:?}",
+                descriptor.name, descriptor.format
+            ))?
+        }
+        Self::validate_sample_count(
+            descriptor.format,
+            descriptor.sample_count.resolve(self.sample_count),
+        )?;
+
+        let id = self.next_render_target_id();
+        self.color_render_targets.insert(
+            id,
+            RenderTarget::new(device, window_size, self.sample_count, descriptor),
+        );
+
+        Ok(ColorRenderTargetKey::Other(id))
+    }
+
+    pub fn add_depth_render_target(
+        &mut self,
+        device: &wgpu::Device,
+        window_size: winit::dpi::PhysicalSize<u32>,
+        descriptor: RenderTargetDescriptor,
+    ) -> Result<DepthRenderTargetKey, Box<dyn std::error::Error>> {
+        if !Self::is_depth_format(descriptor.format) {
+            Err(format!(
+                "Depth render target {} must use a depth format, got {:?}",
+                descriptor.name, descriptor.format
+            ))?
+        }
+        Self::validate_sample_count(
+            descriptor.format,
+            descriptor.sample_count.resolve(self.sample_count),
+        )?;
+
+        let id = self.next_render_target_id();
+        self.depth_render_targets.insert(
+            id,
+            RenderTarget::new(device, window_size, self.sample_count, descriptor),
+        );
+
+        Ok(DepthRenderTargetKey::Other(id))
+    }
+
+    pub fn remove_render_target(
+        &mut self,
+        key: RenderTargetKey,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let removed = match key {
+            RenderTargetKey::Color(ColorRenderTargetKey::Other(id)) => {
+                self.color_render_targets.remove(&id).is_some()
+            }
+            RenderTargetKey::Depth(DepthRenderTargetKey::Other(id)) => {
+                self.depth_render_targets.remove(&id).is_some()
+            }
+            _ => Err(format!(
+                "Only user-defined render targets can be removed, not {:?}",
+                key
+            ))?,
+        };
+
+        if !removed {
+            Err(format!("Render target {:?} doesn't exist", key))?
+        }
+        Ok(())
+    }
+
+    pub fn find_color_render_target_key(&self, name: &str) -> Option<ColorRenderTargetKey> {
+        self.color_render_targets
+            .iter()
+            .find(|(_, target)| target.descriptor.name == name)
+            .map(|(id, _)| ColorRenderTargetKey::Other(*id))
+    }
+
+    pub fn find_depth_render_target_key(&self, name: &str) -> Option<DepthRenderTargetKey> {
+        self.depth_render_targets
+            .iter()
+            .find(|(_, target)| target.descriptor.name == name)
+            .map(|(id, _)| DepthRenderTargetKey::Other(*id))
+    }
+
+    // Recreates the render targets whose size or sample count follow the window's
+    pub fn update_window_relative_render_targets(
+        &mut self,
+        device: &wgpu::Device,
+        window_size: winit::dpi::PhysicalSize<u32>,
+    ) {
+        for render_target in self
+            .color_render_targets
+            .values_mut()
+            .chain(self.depth_render_targets.values_mut())
+        {
+            if render_target.needs_recreate(window_size, self.sample_count) {
+                *render_target = RenderTarget::new(
+                    device,
+                    window_size,
+                    self.sample_count,
+                    render_target.descriptor.clone(),
+                );
+                self.render_target_generation += 1;
+            }
+        }
+    }
+
+    fn get_other_render_target(&self, key: RenderTargetKey) -> Option<&RenderTarget> {
+        match key {
+            RenderTargetKey::Color(ColorRenderTargetKey::Other(id)) => {
+                self.color_render_targets.get(&id)
+            }
+            RenderTargetKey::Depth(DepthRenderTargetKey::Other(id)) => {
+                self.depth_render_targets.get(&id)
+            }
+            _ => None,
+        }
+    }
+
+    fn find_other_render_target(&self, key: RenderTargetKey) -> &RenderTarget {
+        self.get_other_render_target(key)
+            .expect("Invalid render target")
+    }
 }
 
 impl Default for RenderTargetCache {
     fn default() -> Self {
         RenderTargetCache {
             depth_buffer_texture: None,
+            offscreen_window_texture: None,
+            multisampled_window_texture: None,
+            sample_count: 1,
+            render_target_generation: 0,
+            render_target_counter: 0,
+            color_render_targets: HashMap::new(),
+            depth_render_targets: HashMap::new(),
         }
     }
 }
 
 impl super::State {
-    pub fn find_render_target(&self, key: RenderTargetKey) -> wgpu::TextureView {
+    pub fn is_headless(&self) -> bool {
+        self.surface.is_none()
+    }
+
+    pub fn window_texture(&self) -> &wgpu::Texture {
+        match self.current_surface_texture.as_ref() {
+            Some(surface_texture) => &surface_texture.texture,
+            None => self
+                .rendertarget_cache
+                .offscreen_window_texture
+                .as_ref()
+                .expect("Window render target is only available during a frame"),
+        }
+    }
+
+    pub fn add_color_render_target(
+        &mut self,
+        descriptor: RenderTargetDescriptor,
+    ) -> Result<ColorRenderTargetKey, Box<dyn std::error::Error>> {
+        self.rendertarget_cache
+            .add_color_render_target(&self.device, self.size, descriptor)
+    }
+
+    pub fn add_depth_render_target(
+        &mut self,
+        descriptor: RenderTargetDescriptor,
+    ) -> Result<DepthRenderTargetKey, Box<dyn std::error::Error>> {
+        self.rendertarget_cache
+            .add_depth_render_target(&self.device, self.size, descriptor)
+    }
+
+    pub fn remove_render_target(
+        &mut self,
+        key: RenderTargetKey,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.rendertarget_cache.remove_render_target(key)
+    }
+
+    pub fn find_color_render_target_key(&self, name: &str) -> Option<ColorRenderTargetKey> {
+        self.rendertarget_cache.find_color_render_target_key(name)
+    }
+
+    pub fn find_depth_render_target_key(&self, name: &str) -> Option<DepthRenderTargetKey> {
+        self.rendertarget_cache.find_depth_render_target_key(name)
+    }
+
+    pub fn find_render_target_texture(&self, key: RenderTargetKey) -> &wgpu::Texture {
         match key {
-            RenderTargetKey::Color(color_key) => match color_key {
-                ColorRenderTargetKey::Invalid => panic!("Invalid render target"),
-                ColorRenderTargetKey::Window => {
-                    let render_target = self.current_surface_texture.as_ref().unwrap();
-                    render_target
-                        .texture
-                        .create_view(&wgpu::TextureViewDescriptor::default())
-                }
-                ColorRenderTargetKey::Other(_) => todo!(),
-            },
-            RenderTargetKey::Depth(depth_key) => match depth_key {
-                DepthRenderTargetKey::Invalid => panic!("Invalid render target"),
-                DepthRenderTargetKey::Window => self
+            RenderTargetKey::Color(ColorRenderTargetKey::Invalid)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Invalid) => {
+                panic!("Invalid render target")
+            }
+            RenderTargetKey::Color(ColorRenderTargetKey::Window) => self.window_texture(),
+            RenderTargetKey::Depth(DepthRenderTargetKey::Window) => self
+                .rendertarget_cache
+                .depth_buffer_texture
+                .as_ref()
+                .unwrap(),
+            other_key => {
+                let render_target = self.rendertarget_cache.find_other_render_target(other_key);
+                render_target
+                    .resolve_texture
+                    .as_ref()
+                    .unwrap_or(&render_target.texture)
+            }
+        }
+    }
+
+    // The view render passes draw to, and the view it's resolved into when multisampling.
+    // find_render_target_texture always returns the single sampled texture.
+    pub fn find_color_render_target_attachment(
+        &self,
+        key: ColorRenderTargetKey,
+    ) -> (wgpu::TextureView, Option<wgpu::TextureView>) {
+        let multisampled_texture = match key {
+            ColorRenderTargetKey::Window => {
+                self.rendertarget_cache.multisampled_window_texture.as_ref()
+            }
+            ColorRenderTargetKey::Other(_) => {
+                let render_target = self
                     .rendertarget_cache
-                    .depth_buffer_texture
+                    .find_other_render_target(RenderTargetKey::Color(key));
+                render_target
+                    .resolve_texture
                     .as_ref()
-                    .unwrap()
-                    .create_view(&wgpu::TextureViewDescriptor::default()),
-                DepthRenderTargetKey::Other(_) => todo!(),
-            },
+                    .map(|_| &render_target.texture)
+            }
+            ColorRenderTargetKey::Invalid => panic!("Invalid render target"),
+        };
+
+        let view = self.find_color_render_target(key);
+        match multisampled_texture {
+            Some(texture) => (
+                texture.create_view(&wgpu::TextureViewDescriptor::default()),
+                Some(view),
+            ),
+            None => (view, None),
+        }
+    }
+
+    pub fn find_render_target_size(&self, key: RenderTargetKey) -> (u32, u32) {
+        match key {
+            RenderTargetKey::Color(ColorRenderTargetKey::Invalid)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Invalid) => {
+                panic!("Invalid render target")
+            }
+            RenderTargetKey::Color(ColorRenderTargetKey::Window)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Window) => {
+                (self.surface_config.width, self.surface_config.height)
+            }
+            other_key => {
+                let render_target = self.rendertarget_cache.find_other_render_target(other_key);
+                (render_target.width, render_target.height)
+            }
         }
     }
 
+    pub fn has_render_target(&self, key: RenderTargetKey) -> bool {
+        match key {
+            RenderTargetKey::Color(ColorRenderTargetKey::Invalid)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Invalid) => false,
+            RenderTargetKey::Color(ColorRenderTargetKey::Window)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Window) => true,
+            other_key => self
+                .rendertarget_cache
+                .get_other_render_target(other_key)
+                .is_some(),
+        }
+    }
+
+    pub fn find_render_target_sample_count(&self, key: RenderTargetKey) -> u32 {
+        match key {
+            RenderTargetKey::Color(ColorRenderTargetKey::Invalid)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Invalid) => {
+                panic!("Invalid render target")
+            }
+            RenderTargetKey::Color(ColorRenderTargetKey::Window)
+            | RenderTargetKey::Depth(DepthRenderTargetKey::Window) => {
+                self.rendertarget_cache.sample_count
+            }
+            other_key => {
+                self.rendertarget_cache
+                    .find_other_render_target(other_key)
+                    .sample_count
+            }
+        }
+    }
+
+    pub fn find_render_target(&self, key: RenderTargetKey) -> wgpu::TextureView {
+        self.find_render_target_texture(key)
+            .create_view(&wgpu::TextureViewDescriptor::default())
+    }
+
+    pub fn find_color_render_target_texture(&self, key: ColorRenderTargetKey) -> &wgpu::Texture {
+        self.find_render_target_texture(RenderTargetKey::Color(key))
+    }
+
+    pub fn find_color_render_target_size(&self, key: ColorRenderTargetKey) -> (u32, u32) {
+        self.find_render_target_size(RenderTargetKey::Color(key))
+    }
+
     pub fn find_color_render_target(&self, key: ColorRenderTargetKey) -> wgpu::TextureView {
         self.find_render_target(RenderTargetKey::Color(key))
     }
@@ -77,7 +542,28 @@ impl super::State {
         match key {
             ColorRenderTargetKey::Invalid => todo!(),
             ColorRenderTargetKey::Window => self.surface_config.format,
-            ColorRenderTargetKey::Other(_) => todo!(),
+            ColorRenderTargetKey::Other(_) => {
+                self.rendertarget_cache
+                    .find_other_render_target(RenderTargetKey::Color(key))
+                    .descriptor
+                    .format
+            }
+        }
+    }
+
+    // Usage of the texture find_color_render_target_texture returns
+    pub fn find_color_render_target_usage(&self, key: ColorRenderTargetKey) -> wgpu::TextureUsages {
+        match key {
+            // Nothing can be done with a render target that doesn't exist
+            ColorRenderTargetKey::Invalid => wgpu::TextureUsages::empty(),
+            ColorRenderTargetKey::Window => self.surface_config.usage,
+            ColorRenderTargetKey::Other(_) => {
+                self.rendertarget_cache
+                    .find_other_render_target(RenderTargetKey::Color(key))
+                    .descriptor
+                    .usage
+                    | wgpu::TextureUsages::RENDER_ATTACHMENT
+            }
         }
     }
 
@@ -88,26 +574,103 @@ impl super::State {
         match key {
             DepthRenderTargetKey::Invalid => todo!(),
             DepthRenderTargetKey::Window => RenderTargetCache::DEPTH_FORMAT,
-            DepthRenderTargetKey::Other(_) => todo!(),
+            DepthRenderTargetKey::Other(_) => {
+                self.rendertarget_cache
+                    .find_other_render_target(RenderTargetKey::Depth(key))
+                    .descriptor
+                    .format
+            }
         }
     }
 
+    pub fn sample_count(&self) -> u32 {
+        self.rendertarget_cache.sample_count
+    }
+
+    pub fn render_target_generation(&self) -> u64 {
+        self.rendertarget_cache.render_target_generation
+    }
+
+    // Multisamples the window render targets and the render targets that use
+    // RenderTargetSampleCount::Window
+    pub fn set_sample_count(
+        &mut self,
+        sample_count: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if sample_count == self.rendertarget_cache.sample_count {
+            return Ok(());
+        }
+
+        RenderTargetCache::validate_sample_count(self.surface_config.format, sample_count)?;
+        RenderTargetCache::validate_sample_count(RenderTargetCache::DEPTH_FORMAT, sample_count)?;
+        for render_target in self
+            .rendertarget_cache
+            .color_render_targets
+            .values()
+            .chain(self.rendertarget_cache.depth_render_targets.values())
+        {
+            if render_target.descriptor.sample_count == RenderTargetSampleCount::Window {
+                RenderTargetCache::validate_sample_count(
+                    render_target.descriptor.format,
+                    sample_count,
+                )?;
+            }
+        }
+
+        self.rendertarget_cache.sample_count = sample_count;
+        self.rendertarget_cache.render_target_generation += 1;
+
+        self.init_builtin_render_targets()?;
+        self.rendertarget_cache
+            .update_window_relative_render_targets(&self.device, self.size);
+
+        Ok(())
+    }
+
     pub fn init_builtin_render_targets(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        self.rendertarget_cache.depth_buffer_texture =
-            Some(self.device.create_texture(&wgpu::TextureDescriptor {
-                label: Some("Depth texture"),
-                size: wgpu::Extent3d {
-                    width: self.size.width,
-                    height: self.size.height,
-                    depth_or_array_layers: 1,
-                },
-                mip_level_count: 1,
-                sample_count: 1,
-                dimension: wgpu::TextureDimension::D2,
-                format: RenderTargetCache::DEPTH_FORMAT,
-                usage: wgpu::TextureUsages::RENDER_ATTACHMENT
-                    | wgpu::TextureUsages::TEXTURE_BINDING,
-            }));
+        if self.is_headless() {
+            self.rendertarget_cache.offscreen_window_texture =
+                Some(self.device.create_texture(&wgpu::TextureDescriptor {
+                    label: Some("Offscreen window texture"),
+                    size: wgpu::Extent3d {
+                        width: self.size.width,
+                        height: self.size.height,
+                        depth_or_array_layers: 1,
+                    },
+                    mip_level_count: 1,
+                    sample_count: 1,
+                    dimension: wgpu::TextureDimension::D2,
+                    format: self.surface_config.format,
+                    usage: self.surface_config.usage,
+                }));
+        }
+
+        let sample_count = self.rendertarget_cache.sample_count;
+        self.rendertarget_cache.multisampled_window_texture = (sample_count > 1).then(|| {
+            create_render_target_texture(
+                &self.device,
+                "Multisampled window texture",
+                (self.size.width, self.size.height),
+                sample_count,
+                self.surface_config.format,
+                wgpu::TextureUsages::RENDER_ATTACHMENT,
+            )
+        });
+
+        self.rendertarget_cache.depth_buffer_texture = Some(create_render_target_texture(
+            &self.device,
+            "Depth texture",
+            (self.size.width, self.size.height),
+            sample_count,
+            RenderTargetCache::DEPTH_FORMAT,
+            // Multisampled depth can't be sampled like a regular depth texture, and GL can't
+            // mix multisampled textures with the multisampled color renderbuffers
+            if sample_count > 1 {
+                wgpu::TextureUsages::RENDER_ATTACHMENT
+            } else {
+                wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING
+            },
+        ));
         Ok(())
     }
 }
@@ -1,8 +1,9 @@
 use bitflags::bitflags;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
     future::Future,
-    hash::Hash,
+    hash::{Hash, Hasher},
     sync::{
         atomic::{AtomicU32, Ordering},
         mpsc, Arc, Condvar, Mutex,
@@ -12,27 +13,40 @@ use std::{
 
 use crate::data;
 
-use super::shader_attributes::find_vertex_attributes;
+use super::bindgroup::BindGroupLayoutKey;
+use super::shader_attributes::reflect_vertex_attributes;
+use super::shader_constants::specialize_shader_source;
+use super::shader_diagnostics::{
+    format_glsl_errors, format_parse_error, format_spirv_error, format_validation_error,
+    report_shader_error,
+};
+#[cfg(not(target_family = "wasm"))]
+use super::shader_hot_reload::ShaderWatcher;
+use super::shader_preprocessor::preprocess_shader;
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
 pub enum VertexShaderKey {
     #[default]
     Invalid,
     PassthroughVS,
     GltfVS,
+    GltfSkinnedVS,
+    PbrVS,
+    PbrSkinnedVS,
     Other(u32),
 }
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default)]
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Default, Serialize, Deserialize)]
 pub enum PixelShaderKey {
     #[default]
     Invalid,
     PassthroughPS,
     GltfPS,
+    PbrPS,
     Other(u32),
 }
 
-#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Hash, Eq, PartialEq, Debug, Serialize, Deserialize)]
 pub enum ShaderKey {
     VS(VertexShaderKey),
     PS(PixelShaderKey),
@@ -41,6 +55,8 @@ pub enum ShaderKey {
 pub struct ShaderRef<'a> {
     pub module: owning_ref::RwLockReadGuardRef<'a, ShaderCache, wgpu::ShaderModule>,
     pub entrypoint: owning_ref::RwLockReadGuardRef<'a, ShaderCache, str>,
+    // Hash of the compiled source, None for the fallback shaders
+    pub content_hash: Option<u64>,
 }
 
 struct ShaderDetails {
@@ -51,7 +67,17 @@ struct ShaderDetails {
 struct VertexShaderDetails {
     stride: u64,
     attributes: Vec<wgpu::VertexAttribute>,
-    buffer_layout: Vec<wgpu::VertexBufferLayout<'static>>,
+}
+
+impl VertexShaderDetails {
+    fn new(attributes: Vec<wgpu::VertexAttribute>) -> Self {
+        let mut stride: u64 = 0;
+        for attribute in attributes.iter() {
+            stride += attribute.format.size();
+        }
+
+        Self { stride, attributes }
+    }
 }
 
 pub enum ShaderEntrypoints<'a> {
@@ -62,13 +88,37 @@ pub enum ShaderEntrypoints<'a> {
 #[derive(Copy, Clone, Hash, Eq, PartialEq)]
 pub struct ShaderModuleHandle(u32);
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum ShaderModuleConstant {
     Bool(bool),
+    Int(i32),
+    Float(f32),
 }
 
+// Each set of constants is a separate permutation of the module at `path`
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ShaderModuleDescriptor {
-    path: String,
-    constants: Vec<(String, ShaderModuleConstant)>,
+    pub path: String,
+    pub constants: Vec<(String, ShaderModuleConstant)>,
+}
+
+impl ShaderModuleDescriptor {
+    pub fn permutation_hash(&self) -> u64 {
+        let mut constants: Vec<&(String, ShaderModuleConstant)> = self.constants.iter().collect();
+        constants.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut hasher = seahash::SeaHasher::new();
+        self.path.hash(&mut hasher);
+        for (name, constant) in constants {
+            name.hash(&mut hasher);
+            match constant {
+                ShaderModuleConstant::Bool(value) => (0u8, *value).hash(&mut hasher),
+                ShaderModuleConstant::Int(value) => (1u8, *value).hash(&mut hasher),
+                ShaderModuleConstant::Float(value) => (2u8, value.to_bits()).hash(&mut hasher),
+            }
+        }
+        hasher.finish()
+    }
 }
 
 bitflags! {
@@ -81,8 +131,38 @@ bitflags! {
 struct CompileShaderTask {
     handle: ShaderModuleHandle,
     descriptor: ShaderModuleDescriptor,
+    shader_buf: Vec<u8>,
+    vertex_attributes: HashMap<String, Vec<wgpu::VertexAttribute>>,
+}
+
+struct ShaderCompileError {
+    handle: ShaderModuleHandle,
+    // Formatted diagnostic, mapped back to the original source files
+    message: String,
+}
+
+struct ProcessedShader {
+    handle: ShaderModuleHandle,
+    module: wgpu::ShaderModule,
+    content_hash: u64,
+    // Reflected vertex inputs by entrypoint, they can change when a shader is reloaded
+    vertex_attributes: HashMap<String, Vec<wgpu::VertexAttribute>>,
 }
 
+// Embedded so it's available before anything is loaded, includes are resolved at startup
+const FALLBACK_SHADER_FILE: &str = "fallback.wgsl";
+const FALLBACK_SHADER_SOURCES: &[(&str, &str)] = &[
+    (
+        FALLBACK_SHADER_FILE,
+        include_str!("../../shaders/fallback.wgsl"),
+    ),
+    (
+        "include/common.wgsl",
+        include_str!("../../shaders/include/common.wgsl"),
+    ),
+];
+const FALLBACK_PS_ENTRYPOINT: &str = "ps_fallback";
+
 struct FenceTask {}
 
 enum ShaderProcessorTask {
@@ -119,7 +199,9 @@ impl Default for ShaderProcessorFutureRunner {
 struct ShaderProcessor {
     device: Arc<wgpu::Device>,
     shaders_to_process_rx: mpsc::Receiver<ShaderProcessorTask>,
-    processed_shaders: mpsc::Sender<(ShaderModuleHandle, wgpu::ShaderModule)>,
+    processed_shaders: mpsc::Sender<ProcessedShader>,
+    compile_errors: mpsc::Sender<ShaderCompileError>,
+    error_scope_lock: Arc<Mutex<()>>,
     fence_condvar: Arc<(Mutex<u32>, Condvar)>,
     compile_task_counter: Arc<AtomicU32>,
 }
@@ -128,49 +210,94 @@ impl ShaderProcessor {
     async fn compile_shader(
         device: Arc<wgpu::Device>,
         shader_to_process: CompileShaderTask,
-        processed_shaders: mpsc::Sender<(ShaderModuleHandle, wgpu::ShaderModule)>,
+        processed_shaders: mpsc::Sender<ProcessedShader>,
+        compile_errors: mpsc::Sender<ShaderCompileError>,
+        error_scope_lock: Arc<Mutex<()>>,
         fence_condvar: Arc<(Mutex<u32>, Condvar)>,
         compile_task_counter: Arc<AtomicU32>,
     ) {
-        let path = shader_to_process.descriptor.path.as_str();
+        match Self::create_shader_module(&device, &error_scope_lock, &shader_to_process).await {
+            Ok(new_shader_module) => processed_shaders
+                .send(ProcessedShader {
+                    handle: shader_to_process.handle,
+                    module: new_shader_module,
+                    content_hash: seahash::hash(&shader_to_process.shader_buf),
+                    vertex_attributes: shader_to_process.vertex_attributes,
+                })
+                .unwrap(),
+            Err(message) => compile_errors
+                .send(ShaderCompileError {
+                    handle: shader_to_process.handle,
+                    message,
+                })
+                .unwrap(),
+        }
 
-        let shader_buf = data::read_bytes(path).await.unwrap();
+        {
+            // Tasks that finish before a fence is submitted aren't part of its count
+            let mut counter = fence_condvar.0.lock().unwrap();
+            *counter = counter.saturating_sub(1);
+            fence_condvar.1.notify_all();
 
-        let shader_source = {
-            if path.ends_with(".wgsl") {
-                wgpu::ShaderSource::Wgsl(std::borrow::Cow::from(
-                    std::str::from_utf8(&shader_buf[..]).unwrap(),
-                ))
-            }
-            /*else if path.ends_with(".spv") {
-                let (prefix, shader_buf_u32, suffix) =
-                    unsafe { shader_buf[..].align_to::<u32>() };
-                if !prefix.is_empty() || !suffix.is_empty() {
-                    Err(format!("Invalid spir-v file {}", path))?
-                }
+            compile_task_counter.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
 
-                wgpu::ShaderSource::SpirV(std::borrow::Cow::from(shader_buf_u32))
-            }*/
-            else {
-                panic!("Unsupported shader format");
+    async fn create_shader_module(
+        device: &wgpu::Device,
+        error_scope_lock: &Mutex<()>,
+        shader_to_process: &CompileShaderTask,
+    ) -> Result<wgpu::ShaderModule, String> {
+        let path = shader_to_process.descriptor.path.as_str();
+        let format = ShaderSourceFormat::from_path(path)?;
+
+        // wgpu reports invalid shaders as a device error without any source locations, so catch
+        // everything naga can find first, with the same capabilities wgpu validates with
+        let (module, source) = parse_shader_source(path, format, &shader_to_process.shader_buf)?;
+        naga::valid::Validator::new(
+            naga::valid::ValidationFlags::all(),
+            validation_capabilities(device.features()),
+        )
+        .validate(&module)
+        .map_err(|e| format_validation_error(path, source.unwrap_or_default(), &e))?;
+
+        let source = match format {
+            ShaderSourceFormat::Wgsl => {
+                wgpu::ShaderSource::Wgsl(std::borrow::Cow::from(source.unwrap()))
+            }
+            ShaderSourceFormat::SpirV => {
+                // The buffer isn't necessarily aligned for u32, so copy the words out
+                let words: Vec<u32> = shader_to_process
+                    .shader_buf
+                    .chunks_exact(4)
+                    .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+                    .collect();
+                wgpu::ShaderSource::SpirV(std::borrow::Cow::from(words))
             }
+            ShaderSourceFormat::Glsl(stage) => wgpu::ShaderSource::Glsl {
+                shader: std::borrow::Cow::from(source.unwrap()),
+                stage,
+                defines: Default::default(),
+            },
         };
 
-        let new_shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
-            label: Some(path),
-            source: shader_source,
-        });
-
-        processed_shaders
-            .send((shader_to_process.handle, new_shader_module))
-            .unwrap();
-
-        {
-            let mut counter = fence_condvar.0.lock().unwrap();
-            *counter = counter.checked_sub(1).unwrap();
-            fence_condvar.1.notify_all();
+        // Error scopes are global to the device, so errors from anything else created while the
+        // scope is pushed end up here too. Scopes are only pushed under the lock, which keeps
+        // concurrent compiles from popping each other's errors.
+        let (shader_module, error_future) = {
+            let _error_scope_guard = error_scope_lock.lock().unwrap();
+
+            device.push_error_scope(wgpu::ErrorFilter::Validation);
+            let shader_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+                label: Some(path),
+                source,
+            });
+            (shader_module, device.pop_error_scope())
+        };
 
-            compile_task_counter.fetch_sub(1, Ordering::AcqRel);
+        match error_future.await {
+            Some(error) => Err(format!("error: {}\n  --> {}\n", error, path)),
+            None => Ok(shader_module),
         }
     }
 
@@ -198,6 +325,8 @@ impl ShaderProcessor {
                         self.device.clone(),
                         compile_shader_task,
                         self.processed_shaders.clone(),
+                        self.compile_errors.clone(),
+                        self.error_scope_lock.clone(),
                         self.fence_condvar.clone(),
                         self.compile_task_counter.clone(),
                     ))
@@ -212,32 +341,184 @@ impl ShaderProcessor {
     }
 }
 
+#[derive(Copy, Clone, PartialEq)]
+enum ShaderSourceFormat {
+    Wgsl,
+    SpirV,
+    // GLSL files hold a single stage, named by the extension
+    Glsl(naga::ShaderStage),
+}
+
+impl ShaderSourceFormat {
+    fn from_path(path: &str) -> Result<Self, String> {
+        match path.rsplit_once('.').map(|(_, extension)| extension) {
+            Some("wgsl") => Ok(Self::Wgsl),
+            Some("spv") => Ok(Self::SpirV),
+            Some("vert") => Ok(Self::Glsl(naga::ShaderStage::Vertex)),
+            Some("frag") => Ok(Self::Glsl(naga::ShaderStage::Fragment)),
+            Some("comp") => Ok(Self::Glsl(naga::ShaderStage::Compute)),
+            _ => Err(format!(
+                "error: Unsupported shader format, expected .wgsl, .spv, .vert, .frag or .comp\n  --> {}\n",
+                path
+            )),
+        }
+    }
+}
+
+const SPIRV_MAGIC_NUMBER: u32 = 0x0723_0203;
+
+// The capabilities wgpu validates shader modules with
+fn validation_capabilities(features: wgpu::Features) -> naga::valid::Capabilities {
+    let mut capabilities = naga::valid::Capabilities::empty();
+    capabilities.set(
+        naga::valid::Capabilities::PUSH_CONSTANT,
+        features.contains(wgpu::Features::PUSH_CONSTANTS),
+    );
+    capabilities.set(
+        naga::valid::Capabilities::FLOAT64,
+        features.contains(wgpu::Features::SHADER_FLOAT64),
+    );
+    capabilities.set(
+        naga::valid::Capabilities::PRIMITIVE_INDEX,
+        features.contains(wgpu::Features::SHADER_PRIMITIVE_INDEX),
+    );
+    capabilities.set(
+        naga::valid::Capabilities::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
+            | naga::valid::Capabilities::SAMPLER_NON_UNIFORM_INDEXING,
+        features.contains(
+            wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING,
+        ),
+    );
+    capabilities.set(
+        naga::valid::Capabilities::UNIFORM_BUFFER_AND_STORAGE_TEXTURE_ARRAY_NON_UNIFORM_INDEXING,
+        features.contains(
+            wgpu::Features::UNIFORM_BUFFER_AND_STORAGE_TEXTURE_ARRAY_NON_UNIFORM_INDEXING,
+        ),
+    );
+    capabilities
+}
+
+// Returns the module and, for text formats, the source it was parsed from
+fn parse_shader_source<'a>(
+    path: &str,
+    format: ShaderSourceFormat,
+    shader_buf: &'a [u8],
+) -> Result<(naga::Module, Option<&'a str>), String> {
+    if format == ShaderSourceFormat::SpirV {
+        let magic_number = shader_buf
+            .get(0..4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()));
+        if shader_buf.len() % 4 != 0 || magic_number != Some(SPIRV_MAGIC_NUMBER) {
+            Err(format!(
+                "error: Shader isn't little endian SPIR-V\n  --> {}\n",
+                path
+            ))?
+        }
+
+        let module = naga::front::spv::parse_u8_slice(shader_buf, &Default::default())
+            .map_err(|e| format_spirv_error(path, &e))?;
+        return Ok((module, None));
+    }
+
+    let source = std::str::from_utf8(shader_buf)
+        .map_err(|_| format!("error: Shader isn't valid utf-8\n  --> {}\n", path))?;
+
+    let module = match format {
+        ShaderSourceFormat::Glsl(stage) => naga::front::glsl::Parser::default()
+            .parse(&naga::front::glsl::Options::from(stage), source)
+            .map_err(|errors| format_glsl_errors(path, source, &errors))?,
+        _ => naga::front::wgsl::parse_str(source)
+            .map_err(|e| format_parse_error(path, source, &e))?,
+    };
+
+    Ok((module, Some(source)))
+}
+
+// Applies the descriptor's constants and reflects the result, returns the source to compile
+fn prepare_shader_source(
+    descriptor: &ShaderModuleDescriptor,
+    shader_buf: &[u8],
+) -> Result<(Vec<u8>, HashMap<String, Vec<wgpu::VertexAttribute>>), Box<dyn std::error::Error>> {
+    let path = descriptor.path.as_str();
+    let format = ShaderSourceFormat::from_path(path)?;
+
+    // Constants are only applied to WGSL, other formats are compiled as they are
+    let shader_buf = match format {
+        ShaderSourceFormat::Wgsl => specialize_shader_source(
+            path,
+            std::str::from_utf8(shader_buf)?,
+            &descriptor.constants,
+        )?
+        .into_bytes(),
+        _ if !descriptor.constants.is_empty() => Err(format!(
+            "Shader constants are only supported for WGSL shaders, not {}",
+            path
+        ))?,
+        _ => shader_buf.to_vec(),
+    };
+
+    let (module, _) = parse_shader_source(path, format, &shader_buf)?;
+    let vertex_attributes = reflect_vertex_attributes(path, &module)?;
+
+    Ok((shader_buf, vertex_attributes))
+}
+
 pub struct ShaderCache {
     frame_status: ShaderCacheFrameStatus,
     shader_module_handle_counter: AtomicU32,
     shaders: HashMap<ShaderKey, ShaderDetails>,
     shader_modules: HashMap<ShaderModuleHandle, wgpu::ShaderModule>,
+    // Hash of the source each module was compiled from, constants already applied
+    shader_module_content_hashes: HashMap<ShaderModuleHandle, u64>,
+    shader_module_descriptors: HashMap<ShaderModuleHandle, ShaderModuleDescriptor>,
+    // Modules by `ShaderModuleDescriptor::permutation_hash`, so each permutation is only built once
+    shader_module_permutations: HashMap<u64, ShaderModuleHandle>,
+    // Modules whose latest compile failed, they're replaced by the fallback shaders until fixed
+    failed_shader_modules: HashMap<ShaderModuleHandle, String>,
     vertex_shader_details: HashMap<VertexShaderKey, VertexShaderDetails>,
+    // Stand-in for shaders that are pending or failed to compile
+    fallback_module: wgpu::ShaderModule,
+    // Bumped whenever a shader module is (re)compiled or fails to, pipelines built before are stale
+    shader_generation: u64,
+    #[cfg(not(target_family = "wasm"))]
+    shader_watcher: Option<ShaderWatcher>,
     #[cfg(not(target_family = "wasm"))]
     shader_processor_thread: Option<JoinHandle<()>>,
     shader_processor_fence_condvar: Arc<(Mutex<u32>, Condvar)>,
     #[cfg(target_family = "wasm")]
     shader_processor: ShaderProcessor,
     shaders_to_process_tx: mpsc::Sender<ShaderProcessorTask>,
-    processed_shaders: mpsc::Receiver<(ShaderModuleHandle, wgpu::ShaderModule)>,
+    processed_shaders: mpsc::Receiver<ProcessedShader>,
+    shader_compile_errors: mpsc::Receiver<ShaderCompileError>,
 }
 
 impl ShaderCache {
     pub fn new(device: Arc<wgpu::Device>) -> ShaderCache {
         let (shaders_to_process_tx, shaders_to_process_rx) = mpsc::channel();
         let (processed_shaders_tx, processed_shaders_rx) = mpsc::channel();
+        let (shader_compile_errors_tx, shader_compile_errors_rx) = mpsc::channel();
 
         let shader_processor_fence_condvar = Arc::new((Mutex::new(0), Condvar::new()));
 
+        let fallback_source = preprocess_shader(FALLBACK_SHADER_FILE, |file| {
+            FALLBACK_SHADER_SOURCES
+                .iter()
+                .find(|(source_file, _)| *source_file == file)
+                .map(|(_, source)| source.to_string())
+                .ok_or(format!("Fallback shader include {} isn't embedded", file))
+        })
+        .unwrap();
+        let fallback_module = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some(FALLBACK_SHADER_FILE),
+            source: wgpu::ShaderSource::Wgsl(std::borrow::Cow::from(fallback_source)),
+        });
+
         let shader_processor = ShaderProcessor {
             device,
             shaders_to_process_rx: shaders_to_process_rx,
             processed_shaders: processed_shaders_tx,
+            compile_errors: shader_compile_errors_tx,
+            error_scope_lock: Arc::new(Mutex::new(())),
             fence_condvar: shader_processor_fence_condvar.clone(),
             compile_task_counter: Arc::new(AtomicU32::new(0)),
         };
@@ -247,7 +528,15 @@ impl ShaderCache {
             shader_module_handle_counter: AtomicU32::new(1),
             shaders: HashMap::new(),
             shader_modules: HashMap::new(),
+            shader_module_content_hashes: HashMap::new(),
+            shader_module_descriptors: HashMap::new(),
+            shader_module_permutations: HashMap::new(),
+            failed_shader_modules: HashMap::new(),
             vertex_shader_details: HashMap::new(),
+            fallback_module,
+            shader_generation: 0,
+            #[cfg(not(target_family = "wasm"))]
+            shader_watcher: None,
             #[cfg(not(target_family = "wasm"))]
             shader_processor_thread: Some(std::thread::spawn(move || shader_processor.process())),
             shader_processor_fence_condvar,
@@ -255,6 +544,7 @@ impl ShaderCache {
             shader_processor,
             shaders_to_process_tx: shaders_to_process_tx,
             processed_shaders: processed_shaders_rx,
+            shader_compile_errors: shader_compile_errors_rx,
         }
     }
 
@@ -271,9 +561,107 @@ impl ShaderCache {
             }
         }
 
-        while let Ok((shader_module_handle, shader_module)) = self.processed_shaders.try_recv() {
+        while let Ok(mut processed_shader) = self.processed_shaders.try_recv() {
+            for (shader_key, shader) in &self.shaders {
+                if let ShaderKey::VS(key) = shader_key {
+                    if shader.module_handle != processed_shader.handle {
+                        continue;
+                    }
+
+                    if let Some(attributes) = processed_shader
+                        .vertex_attributes
+                        .remove(&shader.entrypoint)
+                    {
+                        self.vertex_shader_details
+                            .insert(*key, VertexShaderDetails::new(attributes));
+                    }
+                }
+            }
+
+            self.failed_shader_modules.remove(&processed_shader.handle);
             self.shader_modules
-                .insert(shader_module_handle, shader_module);
+                .insert(processed_shader.handle, processed_shader.module);
+            self.shader_module_content_hashes
+                .insert(processed_shader.handle, processed_shader.content_hash);
+            self.shader_generation += 1;
+        }
+
+        while let Ok(compile_error) = self.shader_compile_errors.try_recv() {
+            report_shader_error(&compile_error.message);
+            self.failed_shader_modules
+                .insert(compile_error.handle, compile_error.message);
+            self.shader_generation += 1;
+        }
+    }
+
+    fn submit_compile_shader_task(
+        &mut self,
+        handle: ShaderModuleHandle,
+        descriptor: ShaderModuleDescriptor,
+        shader_buf: Vec<u8>,
+        vertex_attributes: HashMap<String, Vec<wgpu::VertexAttribute>>,
+    ) {
+        self.shader_module_descriptors
+            .insert(handle, descriptor.clone());
+
+        self.submit_shader_processor_task(ShaderProcessorTask::CompileShader(CompileShaderTask {
+            handle,
+            descriptor,
+            shader_buf,
+            vertex_attributes,
+        }));
+    }
+
+    // The previous module stays in use until the reloaded one is compiled
+    #[cfg(not(target_family = "wasm"))]
+    fn reload_shader_module(&mut self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let handles: Vec<(ShaderModuleHandle, ShaderModuleDescriptor)> = self
+            .shader_module_descriptors
+            .iter()
+            .filter(|(_, descriptor)| descriptor.path == path)
+            .map(|(handle, descriptor)| (*handle, descriptor.clone()))
+            .collect();
+
+        for (handle, descriptor) in handles {
+            let shader_buf =
+                std::fs::read(path).map_err(|_| format!("Failed to read file {}", path))?;
+
+            match prepare_shader_source(&descriptor, &shader_buf) {
+                Ok((shader_buf, vertex_attributes)) => self.submit_compile_shader_task(
+                    handle,
+                    descriptor,
+                    shader_buf,
+                    vertex_attributes,
+                ),
+                Err(e) => {
+                    report_shader_error(&e.to_string());
+                    self.failed_shader_modules.insert(handle, e.to_string());
+                    self.shader_generation += 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn reload_changed_shaders(&mut self) {
+        let rebuilt_shaders = match self.shader_watcher.as_ref() {
+            Some(shader_watcher) => shader_watcher.rebuild_changed_shaders(),
+            None => return,
+        };
+
+        // A broken shader shouldn't take the app down while it's being edited, or keep the other
+        // shaders from reloading
+        match rebuilt_shaders {
+            Ok(rebuilt_shaders) => {
+                for path in rebuilt_shaders {
+                    if let Err(e) = self.reload_shader_module(&path) {
+                        report_shader_error(&e.to_string());
+                    }
+                }
+            }
+            Err(e) => report_shader_error(&e.to_string()),
         }
     }
 
@@ -290,31 +678,87 @@ impl ShaderCache {
             self.shader_processor.process();
         }
 
+        #[cfg(not(target_family = "wasm"))]
+        {
+            self.reload_changed_shaders();
+        }
+
         self.receive_processed_shaders();
         return self.frame_status;
     }
 }
 
-impl super::State {
-    pub fn is_shader_ready(&self, shader_key: ShaderKey) -> bool {
-        let shader_cache = self.shader_cache.read().unwrap();
-        shader_cache.shaders.get(&shader_key).is_some()
+// The processor thread holds on to the device, so it has to finish before the device can go
+#[cfg(not(target_family = "wasm"))]
+impl Drop for ShaderCache {
+    fn drop(&mut self) {
+        // Closing the task channel ends the processor loop
+        let (closed_tx, _) = mpsc::channel();
+        drop(std::mem::replace(
+            &mut self.shaders_to_process_tx,
+            closed_tx,
+        ));
+
+        if let Some(shader_processor_thread) = self.shader_processor_thread.take() {
+            let _ = shader_processor_thread.join();
+        }
     }
+}
 
-    pub fn use_shader<'a>(&'a self, shader_key: ShaderKey) -> ShaderRef<'a> {
-        let shader_cache = self.shader_cache.read().unwrap();
-        let shader = shader_cache.shaders.get(&shader_key).unwrap();
+impl ShaderCache {
+    fn is_shader_ready(&self, shader_key: ShaderKey) -> bool {
+        self.shaders.get(&shader_key).map_or(false, |shader| {
+            self.shader_modules.contains_key(&shader.module_handle)
+                && !self
+                    .failed_shader_modules
+                    .contains_key(&shader.module_handle)
+        })
+    }
 
-        if !shader_cache
-            .shader_modules
-            .contains_key(&shader.module_handle)
-        {
-            todo!("Fallback to compatible proxy shader")
+    // The fallback vertex shaders only read the position, its format picks the entrypoint
+    fn fallback_vs_entrypoint(&self, vs: VertexShaderKey, transform: bool) -> &'static str {
+        let position_format = self.vertex_shader_details.get(&vs).and_then(|details| {
+            details
+                .attributes
+                .iter()
+                .find(|attribute| attribute.shader_location == 0)
+                .map(|attribute| attribute.format)
+        });
+
+        match (position_format, transform) {
+            (Some(wgpu::VertexFormat::Float32x2), false) => "vs_fallback_vec2",
+            (Some(wgpu::VertexFormat::Float32x3), false) => "vs_fallback_vec3",
+            (Some(wgpu::VertexFormat::Float32x4), false) => "vs_fallback_vec4",
+            (Some(wgpu::VertexFormat::Float32x2), true) => "vs_fallback_world_vec2",
+            (Some(wgpu::VertexFormat::Float32x3), true) => "vs_fallback_world_vec3",
+            (Some(wgpu::VertexFormat::Float32x4), true) => "vs_fallback_world_vec4",
+            _ => "vs_fallback_empty",
         }
+    }
+}
+
+impl super::State {
+    pub fn is_shader_ready(&self, shader_key: ShaderKey) -> bool {
+        self.shader_cache
+            .read()
+            .unwrap()
+            .is_shader_ready(shader_key)
+    }
+
+    fn use_shader<'a>(&'a self, shader_key: ShaderKey) -> ShaderRef<'a> {
+        let content_hash = {
+            let shader_cache = self.shader_cache.read().unwrap();
+            let shader = shader_cache.shaders.get(&shader_key).unwrap();
+            shader_cache
+                .shader_module_content_hashes
+                .get(&shader.module_handle)
+                .copied()
+        };
 
         ShaderRef {
             module: owning_ref::RwLockReadGuardRef::new(self.shader_cache.read().unwrap()).map(
                 |shader_cache| {
+                    let shader = shader_cache.shaders.get(&shader_key).unwrap();
                     shader_cache
                         .shader_modules
                         .get(&shader.module_handle)
@@ -331,78 +775,204 @@ impl super::State {
                         .as_str()
                 },
             ),
+            content_hash,
+        }
+    }
+
+    fn use_fallback_shader<'a>(&'a self, entrypoint: &'static str) -> ShaderRef<'a> {
+        ShaderRef {
+            module: owning_ref::RwLockReadGuardRef::new(self.shader_cache.read().unwrap())
+                .map(|shader_cache| &shader_cache.fallback_module),
+            entrypoint: owning_ref::RwLockReadGuardRef::new(self.shader_cache.read().unwrap())
+                .map(|_| entrypoint),
+            content_hash: None,
         }
     }
 
-    pub fn find_vertex_buffer_layout<'a>(
+    // Shaders that are still compiling or failed to compile are swapped for the fallback ones.
+    // The fallback vertex shader outputs nothing but a position, so it also needs the fallback
+    // pixel shader.
+    pub fn use_shaders<'a>(
         &'a self,
         vs: VertexShaderKey,
-    ) -> owning_ref::RwLockReadGuardRef<'a, ShaderCache, [wgpu::VertexBufferLayout]> {
-        owning_ref::RwLockReadGuardRef::new(self.shader_cache.read().unwrap()).map(|shader_cache| {
-            shader_cache
-                .vertex_shader_details
-                .get(&vs)
-                .unwrap()
-                .buffer_layout
-                .as_slice()
-        })
+        ps: PixelShaderKey,
+        bind_group_layouts: &[BindGroupLayoutKey],
+    ) -> (ShaderRef<'a>, ShaderRef<'a>) {
+        let (vs_ready, ps_ready, fallback_vs_entrypoint) = {
+            let shader_cache = self.shader_cache.read().unwrap();
+
+            // Positions can only be transformed when the pass binds the params for it
+            let transform = bind_group_layouts.contains(&BindGroupLayoutKey::World)
+                && bind_group_layouts.contains(&BindGroupLayoutKey::Model);
+
+            (
+                shader_cache.is_shader_ready(ShaderKey::VS(vs)),
+                shader_cache.is_shader_ready(ShaderKey::PS(ps)),
+                shader_cache.fallback_vs_entrypoint(vs, transform),
+            )
+        };
+
+        match (vs_ready, ps_ready) {
+            (true, true) => (
+                self.use_shader(ShaderKey::VS(vs)),
+                self.use_shader(ShaderKey::PS(ps)),
+            ),
+            (true, false) => (
+                self.use_shader(ShaderKey::VS(vs)),
+                self.use_fallback_shader(FALLBACK_PS_ENTRYPOINT),
+            ),
+            (false, _) => (
+                self.use_fallback_shader(fallback_vs_entrypoint),
+                self.use_fallback_shader(FALLBACK_PS_ENTRYPOINT),
+            ),
+        }
     }
 
-    pub fn find_vertex_shader_stride(&self, vs: VertexShaderKey) -> u64 {
+    // None for vertex shaders that failed to load, until they're fixed
+    pub fn find_vertex_attributes<'a>(
+        &'a self,
+        vs: VertexShaderKey,
+    ) -> Option<owning_ref::RwLockReadGuardRef<'a, ShaderCache, [wgpu::VertexAttribute]>> {
+        owning_ref::RwLockReadGuardRef::new(self.shader_cache.read().unwrap())
+            .try_map(|shader_cache| {
+                shader_cache
+                    .vertex_shader_details
+                    .get(&vs)
+                    .map(|details| details.attributes.as_slice())
+                    .ok_or(())
+            })
+            .ok()
+    }
+
+    pub fn find_shader_compile_error(&self, shader_key: ShaderKey) -> Option<String> {
+        let shader_cache = self.shader_cache.read().unwrap();
+        let shader = shader_cache.shaders.get(&shader_key)?;
+        shader_cache
+            .failed_shader_modules
+            .get(&shader.module_handle)
+            .cloned()
+    }
+
+    pub fn is_shader_module_loaded(&self, descriptor: &ShaderModuleDescriptor) -> bool {
+        self.shader_cache
+            .read()
+            .unwrap()
+            .shader_module_permutations
+            .contains_key(&descriptor.permutation_hash())
+    }
+
+    // The module a shader comes from and every entrypoint it was added with
+    pub fn find_shader_module_entrypoints(
+        &self,
+        shader_key: ShaderKey,
+    ) -> Option<(ShaderModuleDescriptor, Vec<(ShaderKey, String)>)> {
+        let shader_cache = self.shader_cache.read().unwrap();
+        let module_handle = shader_cache.shaders.get(&shader_key)?.module_handle;
+        let descriptor = shader_cache
+            .shader_module_descriptors
+            .get(&module_handle)?
+            .clone();
+        let entrypoints = shader_cache
+            .shaders
+            .iter()
+            .filter(|(_, shader)| shader.module_handle == module_handle)
+            .map(|(key, shader)| (*key, shader.entrypoint.clone()))
+            .collect();
+
+        Some((descriptor, entrypoints))
+    }
+
+    pub fn shader_generation(&self) -> u64 {
+        self.shader_cache.read().unwrap().shader_generation
+    }
+
+    // Watches the shader sources and recompiles them as they're edited
+    #[cfg(not(target_family = "wasm"))]
+    pub fn enable_shader_hot_reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.shader_cache.write().unwrap().shader_watcher = Some(ShaderWatcher::new()?);
+        Ok(())
+    }
+
+    pub fn find_vertex_shader_stride(&self, vs: VertexShaderKey) -> Option<u64> {
         self.shader_cache
             .read()
             .unwrap()
             .vertex_shader_details
             .get(&vs)
-            .unwrap()
-            .stride
+            .map(|details| details.stride)
     }
 
-    pub fn add_shader_module(
+    pub async fn add_shader_module(
         &mut self,
         descriptor: ShaderModuleDescriptor,
         entrypoints: &[ShaderEntrypoints<'_>],
     ) -> Result<ShaderModuleHandle, Box<dyn std::error::Error>> {
-        let mut shader_cache = self.shader_cache.write().unwrap();
-
         let path = descriptor.path.clone();
 
-        let handle = ShaderModuleHandle(
-            shader_cache
-                .shader_module_handle_counter
-                .fetch_add(1, Ordering::Relaxed),
-        );
+        let shader_buf = data::read_bytes(&path).await?;
 
-        shader_cache.submit_shader_processor_task(ShaderProcessorTask::CompileShader(
-            CompileShaderTask { handle, descriptor },
-        ));
+        // Vertex layouts are reflected up front so models can be built before the module is ready
+        let mut prepared_source =
+            prepare_shader_source(&descriptor, &shader_buf).map_err(|e| e.to_string());
 
-        for entrypoint in entrypoints {
-            match entrypoint {
-                ShaderEntrypoints::VS((key, entrypoint_func)) => {
-                    let attributes = find_vertex_attributes(&path, &entrypoint_func);
+        let mut shader_cache = self.shader_cache.write().unwrap();
 
-                    let mut stride: u64 = 0;
-                    for attribute in attributes.iter() {
-                        stride += attribute.format.size();
+        let permutation_hash = descriptor.permutation_hash();
+        let handle = match shader_cache
+            .shader_module_permutations
+            .get(&permutation_hash)
+        {
+            Some(handle) => *handle,
+            None => {
+                let handle = ShaderModuleHandle(
+                    shader_cache
+                        .shader_module_handle_counter
+                        .fetch_add(1, Ordering::Relaxed),
+                );
+
+                shader_cache
+                    .shader_module_permutations
+                    .insert(permutation_hash, handle);
+
+                // A broken shader is replaced by the fallback shaders until it's fixed, the same
+                // as a failed reload
+                match prepared_source.as_mut() {
+                    Ok((shader_buf, vertex_attributes)) => shader_cache.submit_compile_shader_task(
+                        handle,
+                        descriptor,
+                        std::mem::take(shader_buf),
+                        vertex_attributes.clone(),
+                    ),
+                    Err(message) => {
+                        report_shader_error(message);
+                        shader_cache
+                            .shader_module_descriptors
+                            .insert(handle, descriptor);
+                        shader_cache
+                            .failed_shader_modules
+                            .insert(handle, message.clone());
+                        shader_cache.shader_generation += 1;
                     }
+                }
 
-                    let mut buffer_layout = Vec::new();
-                    buffer_layout.push(wgpu::VertexBufferLayout {
-                        array_stride: stride,
-                        step_mode: wgpu::VertexStepMode::Vertex,
-                        attributes: attributes,
-                    });
-
-                    let vertex_shader_details = VertexShaderDetails {
-                        stride: stride,
-                        attributes: attributes.to_vec(),
-                        buffer_layout,
-                    };
+                handle
+            }
+        };
 
-                    shader_cache
-                        .vertex_shader_details
-                        .insert(*key, vertex_shader_details);
+        for entrypoint in entrypoints {
+            match entrypoint {
+                ShaderEntrypoints::VS((key, entrypoint_func)) => {
+                    // The layout of a failed module is reflected once it compiles
+                    if let Ok((_, vertex_attributes)) = &prepared_source {
+                        let attributes = vertex_attributes.get(*entrypoint_func).ok_or(format!(
+                            "Vertex entrypoint {} not found in {}",
+                            entrypoint_func, path
+                        ))?;
+
+                        shader_cache
+                            .vertex_shader_details
+                            .insert(*key, VertexShaderDetails::new(attributes.clone()));
+                    }
 
                     shader_cache.shaders.insert(
                         ShaderKey::VS(*key),
@@ -441,15 +1011,37 @@ impl super::State {
                 VS((PassthroughVS, "vs_passthrough")),
                 PS((PassthroughPS, "ps_passthrough")),
             ],
-        )?;
+        )
+        .await?;
 
         self.add_shader_module(
             ShaderModuleDescriptor {
                 path: "data/shaders/gltf.wgsl".to_string(),
                 constants: vec![],
             },
-            &[VS((GltfVS, "vs")), PS((GltfPS, "ps"))],
-        )?;
+            &[
+                VS((GltfVS, "vs")),
+                VS((GltfSkinnedVS, "vs_skinned")),
+                PS((GltfPS, "ps")),
+            ],
+        )
+        .await?;
+
+        self.add_shader_module(
+            ShaderModuleDescriptor {
+                path: "data/shaders/pbr.wgsl".to_string(),
+                constants: vec![],
+            },
+            &[
+                VS((PbrVS, "vs")),
+                VS((PbrSkinnedVS, "vs_skinned")),
+                PS((PbrPS, "ps")),
+            ],
+        )
+        .await?;
+
+        #[cfg(not(target_family = "wasm"))]
+        self.prewarm_shader_modules().await;
 
         self.shader_cache
             .write()
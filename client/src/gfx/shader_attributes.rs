@@ -1,26 +1,200 @@
-pub fn find_vertex_attributes(
-    shader_path: &str,
-    vs_entrypoint: &str,
-) -> &'static [wgpu::VertexAttribute] {
-    match (shader_path, vs_entrypoint) {
-        ("data/shaders/passthrough.wgsl", "vs_passthrough") => {
-            const ATTRIBUTES: &'static [wgpu::VertexAttribute] =
-                &wgpu::vertex_attr_array![0 => Float32x3, 1 => Float32x4];
-            ATTRIBUTES
+use std::collections::HashMap;
+
+fn vertex_format(
+    path: &str,
+    type_inner: &naga::TypeInner,
+) -> Result<wgpu::VertexFormat, Box<dyn std::error::Error>> {
+    use naga::{ScalarKind, TypeInner, VectorSize};
+    use wgpu::VertexFormat;
+
+    let format = match *type_inner {
+        TypeInner::Scalar { kind, width: 4 } => match kind {
+            ScalarKind::Float => Some(VertexFormat::Float32),
+            ScalarKind::Sint => Some(VertexFormat::Sint32),
+            ScalarKind::Uint => Some(VertexFormat::Uint32),
+            ScalarKind::Bool => None,
+        },
+        TypeInner::Vector {
+            size,
+            kind,
+            width: 4,
+        } => match (kind, size) {
+            (ScalarKind::Float, VectorSize::Bi) => Some(VertexFormat::Float32x2),
+            (ScalarKind::Float, VectorSize::Tri) => Some(VertexFormat::Float32x3),
+            (ScalarKind::Float, VectorSize::Quad) => Some(VertexFormat::Float32x4),
+            (ScalarKind::Sint, VectorSize::Bi) => Some(VertexFormat::Sint32x2),
+            (ScalarKind::Sint, VectorSize::Tri) => Some(VertexFormat::Sint32x3),
+            (ScalarKind::Sint, VectorSize::Quad) => Some(VertexFormat::Sint32x4),
+            (ScalarKind::Uint, VectorSize::Bi) => Some(VertexFormat::Uint32x2),
+            (ScalarKind::Uint, VectorSize::Tri) => Some(VertexFormat::Uint32x3),
+            (ScalarKind::Uint, VectorSize::Quad) => Some(VertexFormat::Uint32x4),
+            (ScalarKind::Bool, _) => None,
+        },
+        _ => None,
+    };
+
+    Ok(format.ok_or(format!(
+        "Unsupported vertex input type {:?} in {}",
+        type_inner, path
+    ))?)
+}
+
+// Vertex inputs of every vertex entrypoint in the module, packed into a single buffer in
+// location order
+pub fn reflect_vertex_attributes(
+    path: &str,
+    module: &naga::Module,
+) -> Result<HashMap<String, Vec<wgpu::VertexAttribute>>, Box<dyn std::error::Error>> {
+    let mut vertex_attributes = HashMap::new();
+
+    for entry_point in module
+        .entry_points
+        .iter()
+        .filter(|entry_point| entry_point.stage == naga::ShaderStage::Vertex)
+    {
+        let mut inputs: Vec<(u32, wgpu::VertexFormat)> = Vec::new();
+
+        for argument in &entry_point.function.arguments {
+            match (&argument.binding, &module.types[argument.ty].inner) {
+                (Some(naga::Binding::Location { location, .. }), type_inner) => {
+                    inputs.push((*location, vertex_format(path, type_inner)?));
+                }
+                (None, naga::TypeInner::Struct { members, .. }) => {
+                    for member in members {
+                        if let Some(naga::Binding::Location { location, .. }) = member.binding {
+                            inputs.push((
+                                location,
+                                vertex_format(path, &module.types[member.ty].inner)?,
+                            ));
+                        }
+                    }
+                }
+                // Builtins like vertex_index don't come from the vertex buffer
+                _ => {}
+            }
         }
-        ("data/shaders/gltf.wgsl", "vs") => {
-            const ATTRIBUTES: &'static [wgpu::VertexAttribute] = &wgpu::vertex_attr_array![
-                0 => Float32x3,
-                1 => Float32x3,
-                2 => Float32x4,
-                3 => Float32x2,
-                4 => Float32x2,
-                5 => Float32x4,
-                6 => Uint32x4,
-                7 => Float32x4
-            ];
-            ATTRIBUTES
+
+        inputs.sort_by_key(|(location, _)| *location);
+
+        let mut offset = 0;
+        let attributes = inputs
+            .into_iter()
+            .map(|(location, format)| {
+                let attribute = wgpu::VertexAttribute {
+                    format,
+                    offset,
+                    shader_location: location,
+                };
+                offset += format.size();
+                attribute
+            })
+            .collect();
+
+        vertex_attributes.insert(entry_point.name.clone(), attributes);
+    }
+
+    Ok(vertex_attributes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reflect(
+        source: &str,
+    ) -> Result<HashMap<String, Vec<wgpu::VertexAttribute>>, Box<dyn std::error::Error>> {
+        let module = naga::front::wgsl::parse_str(source).unwrap();
+        reflect_vertex_attributes("test.wgsl", &module)
+    }
+
+    fn attribute(
+        shader_location: u32,
+        format: wgpu::VertexFormat,
+        offset: u64,
+    ) -> wgpu::VertexAttribute {
+        wgpu::VertexAttribute {
+            format,
+            offset,
+            shader_location,
         }
-        _ => panic!(),
+    }
+
+    #[test]
+    fn inputs_are_packed_in_location_order() {
+        let source = "
+            @vertex
+            fn vs( @location(2) weight: f32, @location(0) position: vec3<f32>, @location(1) joints: vec4<u32> ) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(position, weight);
+            }
+        ";
+        let vertex_attributes = reflect(source).unwrap();
+
+        assert_eq!(
+            vertex_attributes["vs"],
+            vec![
+                attribute(0, wgpu::VertexFormat::Float32x3, 0),
+                attribute(1, wgpu::VertexFormat::Uint32x4, 12),
+                attribute(2, wgpu::VertexFormat::Float32, 28),
+            ]
+        );
+    }
+
+    #[test]
+    fn struct_inputs_are_flattened_and_builtins_skipped() {
+        let source = "
+            struct VertexIn {
+                @location(1) uv: vec2<f32>,
+                @builtin(instance_index) instance_index: u32,
+                @location(0) position: vec4<f32>,
+            };
+
+            @vertex
+            fn vs( in: VertexIn, @builtin(vertex_index) vertex_index: u32, @location(3) index: vec2<i32> ) -> @builtin(position) vec4<f32> {
+                return in.position;
+            }
+        ";
+        let vertex_attributes = reflect(source).unwrap();
+
+        assert_eq!(
+            vertex_attributes["vs"],
+            vec![
+                attribute(0, wgpu::VertexFormat::Float32x4, 0),
+                attribute(1, wgpu::VertexFormat::Float32x2, 16),
+                attribute(3, wgpu::VertexFormat::Sint32x2, 24),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_vertex_entrypoints_are_reflected() {
+        let source = "
+            @vertex
+            fn vs_empty( @builtin(vertex_index) vertex_index: u32 ) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+            }
+
+            @fragment
+            fn ps( @location(0) color: vec4<f32> ) -> @location(0) vec4<f32> {
+                return color;
+            }
+        ";
+        let vertex_attributes = reflect(source).unwrap();
+
+        assert_eq!(vertex_attributes.len(), 1);
+        assert!(vertex_attributes["vs_empty"].is_empty());
+    }
+
+    #[test]
+    fn unsupported_input_types_are_rejected() {
+        let source = "
+            @vertex
+            fn vs( @location(0) flag: bool ) -> @builtin(position) vec4<f32> {
+                return vec4<f32>(0.0, 0.0, 0.0, 1.0);
+            }
+        ";
+        let error = reflect(source).unwrap_err().to_string();
+
+        assert!(error.starts_with("Unsupported vertex input type"));
+        assert!(error.ends_with("in test.wgsl"));
     }
 }
@@ -0,0 +1,257 @@
+use super::shader::ShaderModuleConstant;
+
+const OVERRIDE_PREFIX: &str = "override ";
+const ID_ATTRIBUTE_PREFIX: &str = "@id(";
+
+fn format_constant(
+    name: &str,
+    ty: &str,
+    constant: &ShaderModuleConstant,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let value = match (ty, constant) {
+        ("bool", ShaderModuleConstant::Bool(value)) => value.to_string(),
+        ("i32", ShaderModuleConstant::Int(value)) => value.to_string(),
+        ("u32", ShaderModuleConstant::Int(value)) if *value >= 0 => format!("{}u", value),
+        ("f32", ShaderModuleConstant::Float(value)) if value.is_finite() => {
+            // Debug formatting keeps the decimal point on whole numbers
+            format!("{:?}", value)
+        }
+        _ => Err(format!(
+            "Shader constant {} can't be set to {:?}, it's declared as {}",
+            name, constant, ty
+        ))?,
+    };
+
+    Ok(value)
+}
+
+// naga can't parse pipeline overridable constants yet, so `override name: T = default;`
+// declarations are rewritten in place to module scope `let`s with the requested values. Lines
+// are kept as they are so diagnostics still map back to the source files. Constants declared
+// with `@id(n)`, on the same line or the one before, can be set by their name or by `n`.
+pub fn specialize_shader_source(
+    path: &str,
+    source: &str,
+    constants: &[(String, ShaderModuleConstant)],
+) -> Result<String, Box<dyn std::error::Error>> {
+    let mut specialized_source = String::with_capacity(source.len());
+    let mut declared_constants = Vec::new();
+    // An `@id(n)` on a line of its own, with its id, applies to an override on the next line
+    let mut id_attribute_line = None;
+
+    for line in source.lines() {
+        let indent = &line[..line.len() - line.trim_start().len()];
+        let (id, declaration) = match line.trim_start().strip_prefix(ID_ATTRIBUTE_PREFIX) {
+            Some(declaration) => {
+                let (id, declaration) = declaration
+                    .split_once(')')
+                    .ok_or(format!("Unterminated @id attribute in {}", path))?;
+                (Some(id.trim()), declaration.trim_start())
+            }
+            None => (None, line.trim_start()),
+        };
+
+        if let (Some(id), "") = (id, declaration) {
+            if let Some((previous_line, _)) = id_attribute_line.replace((line, id)) {
+                specialized_source += previous_line;
+                specialized_source.push('\n');
+            }
+            continue;
+        }
+        let (id, previous_line) = match id_attribute_line.take() {
+            Some((previous_line, previous_id)) => (id.or(Some(previous_id)), Some(previous_line)),
+            None => (id, None),
+        };
+
+        let declaration = match declaration.strip_prefix(OVERRIDE_PREFIX) {
+            Some(declaration) => declaration,
+            None => {
+                // Left for naga to report, @id only applies to overrides
+                if let Some(previous_line) = previous_line {
+                    specialized_source += previous_line;
+                    specialized_source.push('\n');
+                }
+                specialized_source += line;
+                specialized_source.push('\n');
+                continue;
+            }
+        };
+        // The attribute's line is emptied, so the following lines keep their numbers
+        if previous_line.is_some() {
+            specialized_source.push('\n');
+        }
+
+        let (declaration, rest) = declaration
+            .split_once(';')
+            .ok_or(format!("Unterminated override declaration in {}", path))?;
+        let (declaration, default_value) = match declaration.split_once('=') {
+            Some((declaration, default_value)) => (declaration, Some(default_value.trim())),
+            None => (declaration, None),
+        };
+        let (name, ty) = declaration
+            .split_once(':')
+            .map(|(name, ty)| (name.trim(), ty.trim()))
+            .ok_or(format!("Override declaration without a type in {}", path))?;
+
+        let value = match constants
+            .iter()
+            .find(|(constant_name, _)| constant_name == name || Some(constant_name.as_str()) == id)
+        {
+            Some((_, constant)) => format_constant(name, ty, constant)?,
+            None => default_value
+                .ok_or(format!("No value for shader constant {} in {}", name, path))?
+                .to_string(),
+        };
+
+        specialized_source += &format!("{}let {}: {} = {};{}\n", indent, name, ty, value, rest);
+        declared_constants.push(name);
+        declared_constants.extend(id);
+    }
+    if let Some((previous_line, _)) = id_attribute_line {
+        specialized_source += previous_line;
+        specialized_source.push('\n');
+    }
+
+    // Catches typos, otherwise the constant would silently keep its default
+    for (name, _) in constants {
+        if !declared_constants.contains(&name.as_str()) {
+            Err(format!(
+                "Shader constant {} isn't declared in {}",
+                name, path
+            ))?
+        }
+    }
+
+    Ok(specialized_source)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn specialize(
+        source: &str,
+        constants: &[(&str, ShaderModuleConstant)],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let constants: Vec<_> = constants
+            .iter()
+            .map(|(name, constant)| (name.to_string(), constant.clone()))
+            .collect();
+        specialize_shader_source("test.wgsl", source, &constants)
+    }
+
+    #[test]
+    fn overrides_become_lets_with_the_requested_values() {
+        let source = "override scale: f32 = 1.0;\n  override count: u32 = 4u; // Count\noverride enabled: bool;\nlet other: i32 = 2;\n";
+        let specialized = specialize(
+            source,
+            &[
+                ("scale", ShaderModuleConstant::Float(2.0)),
+                ("enabled", ShaderModuleConstant::Bool(true)),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            specialized,
+            "let scale: f32 = 2.0;\n  let count: u32 = 4u; // Count\nlet enabled: bool = true;\nlet other: i32 = 2;\n"
+        );
+        naga::front::wgsl::parse_str(&specialized).unwrap();
+    }
+
+    #[test]
+    fn id_attributes_are_removed_and_can_name_constants() {
+        let source = "@id(0) override scale: f32 = 1.0;\n@id( 7 )override count: i32 = 4;\n";
+        let specialized = specialize(source, &[("7", ShaderModuleConstant::Int(-3))]).unwrap();
+
+        assert_eq!(specialized, "let scale: f32 = 1.0;\nlet count: i32 = -3;\n");
+        naga::front::wgsl::parse_str(&specialized).unwrap();
+    }
+
+    #[test]
+    fn id_attributes_on_the_previous_line_apply_to_the_override() {
+        let source =
+            "@id(3)\noverride scale: f32 = 1.0;\n  @id(4)\n  override count: i32;\nfn f() {}\n";
+        let specialized = specialize(
+            source,
+            &[
+                ("3", ShaderModuleConstant::Float(0.5)),
+                ("count", ShaderModuleConstant::Int(2)),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            specialized,
+            "\nlet scale: f32 = 0.5;\n\n  let count: i32 = 2;\nfn f() {}\n"
+        );
+        naga::front::wgsl::parse_str(&specialized).unwrap();
+    }
+
+    #[test]
+    fn specialization_keeps_line_numbers() {
+        let source = "// a\noverride a: i32 = 1;\n\nfn f() {}\n";
+        let specialized = specialize(source, &[("a", ShaderModuleConstant::Int(5))]).unwrap();
+
+        assert_eq!(specialized.lines().count(), source.lines().count());
+        assert_eq!(specialized.lines().nth(1), Some("let a: i32 = 5;"));
+    }
+
+    #[test]
+    fn overrides_without_a_value_are_rejected() {
+        let error = specialize("override a: i32;\n", &[]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "No value for shader constant a in test.wgsl"
+        );
+    }
+
+    #[test]
+    fn undeclared_constants_are_rejected() {
+        let source = "@id(1) override a: i32 = 1;\n";
+        let error = specialize(source, &[("b", ShaderModuleConstant::Int(2))]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Shader constant b isn't declared in test.wgsl"
+        );
+
+        let error = specialize(source, &[("2", ShaderModuleConstant::Int(2))]).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Shader constant 2 isn't declared in test.wgsl"
+        );
+    }
+
+    #[test]
+    fn constants_must_match_the_declared_type() {
+        let source = "override a: u32 = 1u;\noverride b: f32 = 1.0;\n";
+
+        assert!(specialize(source, &[("a", ShaderModuleConstant::Int(-1))]).is_err());
+        assert!(specialize(source, &[("a", ShaderModuleConstant::Float(1.0))]).is_err());
+        assert!(specialize(source, &[("b", ShaderModuleConstant::Bool(true))]).is_err());
+        assert_eq!(
+            specialize(source, &[("a", ShaderModuleConstant::Int(3))]).unwrap(),
+            "let a: u32 = 3u;\nlet b: f32 = 1.0;\n"
+        );
+    }
+
+    #[test]
+    fn non_finite_floats_are_rejected() {
+        let source = "override a: f32 = 1.0;\n";
+
+        for value in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            let error =
+                specialize(source, &[("a", ShaderModuleConstant::Float(value))]).unwrap_err();
+            assert!(error
+                .to_string()
+                .starts_with("Shader constant a can't be set to"));
+        }
+    }
+
+    #[test]
+    fn malformed_declarations_are_rejected() {
+        assert!(specialize("override a: i32 = 1\n", &[]).is_err());
+        assert!(specialize("override a = 1;\n", &[]).is_err());
+        assert!(specialize("@id(1 override a: i32 = 1;\n", &[]).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use super::shader_preprocessor::LINE_MARKER_PREFIX;
+
+fn parse_line_marker(line: &str) -> Option<(usize, &str)> {
+    let (marker_line, file) = line.strip_prefix(LINE_MARKER_PREFIX)?.split_once(' ')?;
+    Some((marker_line.parse().ok()?, file.trim().trim_matches('"')))
+}
+
+// Maps a 1-based line of a generated shader back to the file and line it came from
+pub fn map_source_line<'a>(path: &'a str, source: &'a str, line: usize) -> (&'a str, usize) {
+    let mut mapped = (path, line);
+
+    // The marker itself doesn't belong to the file, the line after it is `marker_line`
+    for (index, source_line) in source.lines().take(line.saturating_sub(1)).enumerate() {
+        if let Some((marker_line, file)) = parse_line_marker(source_line) {
+            mapped = (file, marker_line + line - (index + 2));
+        }
+    }
+
+    mapped
+}
+
+fn format_diagnostic(
+    path: &str,
+    source: &str,
+    message: &str,
+    location: Option<naga::SourceLocation>,
+    labels: &[String],
+) -> String {
+    let mut diagnostic = format!("error: {}\n", message);
+
+    if let Some(location) = location {
+        let line = location.line_number as usize;
+        let (file, file_line) = map_source_line(path, source, line);
+
+        diagnostic += &format!("  --> {}:{}:{}\n", file, file_line, location.line_position);
+        if let Some(source_line) = source.lines().nth(line - 1) {
+            diagnostic += &format!("   | {}\n", source_line);
+            diagnostic += &format!(
+                "   | {}^\n",
+                " ".repeat(location.line_position.saturating_sub(1) as usize)
+            );
+        }
+    } else {
+        diagnostic += &format!("  --> {}\n", path);
+    }
+
+    for label in labels.iter().filter(|label| !label.is_empty()) {
+        diagnostic += &format!("   = {}\n", label);
+    }
+
+    diagnostic
+}
+
+pub fn format_parse_error(
+    path: &str,
+    source: &str,
+    error: &naga::front::wgsl::ParseError,
+) -> String {
+    format_diagnostic(
+        path,
+        source,
+        &error.to_string(),
+        error.location(source),
+        &[],
+    )
+}
+
+pub fn format_validation_error(
+    path: &str,
+    source: &str,
+    error: &naga::WithSpan<naga::valid::ValidationError>,
+) -> String {
+    // The outer error only names the failing function, the cause has the details
+    let mut message = error.as_inner().to_string();
+    let mut cause = std::error::Error::source(error.as_inner());
+    while let Some(error) = cause {
+        message += &format!(": {}", error);
+        cause = error.source();
+    }
+
+    let labels: Vec<String> = error.spans().map(|(_, label)| label.clone()).collect();
+
+    // Modules parsed from SPIR-V have no source text to point into
+    let location = if source.is_empty() {
+        None
+    } else {
+        error.location(source)
+    };
+
+    format_diagnostic(path, source, &message, location, &labels)
+}
+
+pub fn format_glsl_errors(path: &str, source: &str, errors: &[naga::front::glsl::Error]) -> String {
+    errors
+        .iter()
+        .map(|error| {
+            let location = error.meta.is_defined().then(|| error.meta.location(source));
+            format_diagnostic(path, source, &error.kind.to_string(), location, &[])
+        })
+        .collect()
+}
+
+pub fn format_spirv_error(path: &str, error: &naga::front::spv::Error) -> String {
+    format_diagnostic(path, "", &error.to_string(), None, &[])
+}
+
+pub fn report_shader_error(message: &str) {
+    #[cfg(target_family = "wasm")]
+    {
+        web_sys::console::error_1(&message.into());
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    {
+        eprintln!("{}", message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gfx::shader_preprocessor::line_marker;
+
+    fn generated_source() -> String {
+        line_marker(1, "main.wgsl")
+            + "main 1\n"
+            + &line_marker(1, "include/common.wgsl")
+            + "common 1\ncommon 2\n"
+            + &line_marker(3, "main.wgsl")
+            + "main 3\n"
+    }
+
+    #[test]
+    fn lines_after_a_marker_start_at_its_line() {
+        let source = generated_source();
+
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 2),
+            ("client/shaders/main.wgsl", 1)
+        );
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 4),
+            ("client/shaders/include/common.wgsl", 1)
+        );
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 5),
+            ("client/shaders/include/common.wgsl", 2)
+        );
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 7),
+            ("client/shaders/main.wgsl", 3)
+        );
+    }
+
+    #[test]
+    fn marker_lines_belong_to_the_previous_file() {
+        let source = generated_source();
+
+        assert_eq!(map_source_line("main.wgsl", &source, 1), ("main.wgsl", 1));
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 3),
+            ("client/shaders/main.wgsl", 2)
+        );
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 6),
+            ("client/shaders/include/common.wgsl", 3)
+        );
+    }
+
+    #[test]
+    fn lines_before_the_first_marker_are_unmapped() {
+        let source = "let a = 1;\nlet b = 2;\n".to_string() + &line_marker(10, "main.wgsl") + "c\n";
+
+        assert_eq!(map_source_line("main.wgsl", &source, 1), ("main.wgsl", 1));
+        assert_eq!(map_source_line("main.wgsl", &source, 2), ("main.wgsl", 2));
+        assert_eq!(
+            map_source_line("main.wgsl", &source, 4),
+            ("client/shaders/main.wgsl", 10)
+        );
+    }
+
+    #[test]
+    fn parse_errors_point_into_included_files() {
+        let source = line_marker(1, "main.wgsl")
+            + "let a: f32 = 1.0;\n"
+            + &line_marker(1, "include/common.wgsl")
+            + "let b: f32 = 2.0;\nlet c: f32 = ;\n";
+        let error = naga::front::wgsl::parse_str(&source).unwrap_err();
+
+        let diagnostic = format_parse_error("main.wgsl", &source, &error);
+        assert!(diagnostic.contains("  --> client/shaders/include/common.wgsl:2:"));
+        assert!(diagnostic.contains("   | let c: f32 = ;\n"));
+    }
+
+    #[test]
+    fn sources_without_markers_are_unmapped() {
+        assert_eq!(map_source_line("a.wgsl", "x\ny\nz\n", 3), ("a.wgsl", 3));
+    }
+}
@@ -0,0 +1,120 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use notify::Watcher;
+
+use super::{
+    shader_diagnostics::report_shader_error,
+    shader_preprocessor::{preprocess_shader, SHADER_SOURCE_DIR},
+};
+
+// Mirrors the layout build.rs uses, paths are relative to the workspace root
+const SHADER_OUTPUT_DIR: &str = "data/shaders";
+// Only included by other shaders, never built on their own
+const SHADER_INCLUDE_DIR: &str = "client/shaders/include";
+
+pub struct ShaderWatcher {
+    // Dropping the watcher stops the events
+    _watcher: notify::RecommendedWatcher,
+    events: mpsc::Receiver<notify::DebouncedEvent>,
+}
+
+impl ShaderWatcher {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let (events_tx, events) = mpsc::channel();
+
+        let mut watcher = notify::watcher(events_tx, Duration::from_millis(100))?;
+        watcher
+            .watch(SHADER_SOURCE_DIR, notify::RecursiveMode::Recursive)
+            .map_err(|e| format!("Failed to watch {}: {}", SHADER_SOURCE_DIR, e))?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    fn changed_source_files(&self) -> HashSet<PathBuf> {
+        let mut changed_source_files = HashSet::new();
+
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                notify::DebouncedEvent::Write(path)
+                | notify::DebouncedEvent::Create(path)
+                | notify::DebouncedEvent::Rename(_, path) => {
+                    if path
+                        .extension()
+                        .map_or(false, |extension| extension == "wgsl")
+                    {
+                        changed_source_files.insert(path);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        changed_source_files
+    }
+
+    // Preprocesses every changed shader again, returns the paths of the regenerated shaders in
+    // `SHADER_OUTPUT_DIR`. Shaders that fail to preprocess are reported and left out, the rest
+    // still get reloaded.
+    pub fn rebuild_changed_shaders(&self) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let changed_source_files = self.changed_source_files();
+        if changed_source_files.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        // Includes aren't tracked per shader, so a changed include rebuilds everything
+        let include_dir = std::fs::canonicalize(SHADER_INCLUDE_DIR)?;
+        let include_changed = changed_source_files
+            .iter()
+            .any(|path| path.starts_with(&include_dir));
+
+        let source_files: Vec<PathBuf> = if include_changed {
+            std::fs::read_dir(SHADER_SOURCE_DIR)?
+                .map_while(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.is_file()
+                        && path
+                            .extension()
+                            .map_or(false, |extension| extension == "wgsl")
+                })
+                .collect()
+        } else {
+            changed_source_files.into_iter().collect()
+        };
+
+        let mut rebuilt_shaders = Vec::new();
+        for source_file in source_files {
+            let file_name = source_file.file_name().unwrap().to_string_lossy();
+
+            let shader_source = match preprocess_shader(&file_name, |file| {
+                let path = Path::new(SHADER_SOURCE_DIR).join(file);
+                std::fs::read_to_string(&path)
+                    .map_err(|_| format!("Failed to read file {}", path.display()))
+            }) {
+                Ok(shader_source) => shader_source,
+                Err(e) => {
+                    report_shader_error(&format!("Failed to preprocess {}:\n{}", file_name, e));
+                    continue;
+                }
+            };
+
+            let output_path = format!("{}/{}", SHADER_OUTPUT_DIR, file_name);
+            if std::fs::write(&output_path, shader_source).is_err() {
+                report_shader_error(&format!("Failed to write file {}", output_path));
+                continue;
+            }
+
+            rebuilt_shaders.push(output_path);
+        }
+
+        Ok(rebuilt_shaders)
+    }
+}
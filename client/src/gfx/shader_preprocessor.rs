@@ -0,0 +1,382 @@
+// Also compiled into build.rs, so this can only depend on std
+use std::collections::HashSet;
+
+// Include paths are relative to this directory, and line markers name files relative to the
+// workspace root
+pub const SHADER_SOURCE_DIR: &str = "client/shaders";
+
+// Preprocessed shaders are a concatenation of several source files, each part starts with a line
+// marker naming the file and line it came from
+pub const LINE_MARKER_PREFIX: &str = "// #line ";
+
+pub fn line_marker(line: usize, file: &str) -> String {
+    format!(
+        "{}{} \"{}/{}\"\n",
+        LINE_MARKER_PREFIX, line, SHADER_SOURCE_DIR, file
+    )
+}
+
+struct Conditional {
+    active: bool,
+    in_else: bool,
+}
+
+struct ShaderPreprocessor<F: FnMut(&str) -> Result<String, String>> {
+    read_file: F,
+    defines: HashSet<String>,
+    include_stack: Vec<String>,
+    output: String,
+}
+
+fn split_directive(line: &str) -> Option<(&str, &str)> {
+    let directive = line.trim().strip_prefix('#')?;
+    Some(
+        directive
+            .split_once(char::is_whitespace)
+            .map_or((directive, ""), |(directive, argument)| {
+                (directive, argument.trim())
+            }),
+    )
+}
+
+// The macro of an #ifndef that wraps the whole file
+fn include_guard(source: &str) -> Option<&str> {
+    let mut lines = source.lines().filter(|line| !line.trim().is_empty());
+    let guard = match split_directive(lines.next()?)? {
+        ("ifndef", guard) => guard,
+        _ => return None,
+    };
+
+    let mut depth = 1;
+    for line in lines {
+        if depth == 0 {
+            return None;
+        }
+        match split_directive(line) {
+            Some(("ifdef" | "ifndef", _)) => depth += 1,
+            Some(("else", _)) if depth == 1 => return None,
+            Some(("endif", _)) => depth -= 1,
+            _ => {}
+        }
+    }
+
+    (depth == 0).then_some(guard)
+}
+
+impl<F: FnMut(&str) -> Result<String, String>> ShaderPreprocessor<F> {
+    fn preprocess_file(&mut self, file: &str) -> Result<(), String> {
+        let source = (self.read_file)(file)?;
+
+        // Files whose include guard is already defined add nothing, so guarded files can include
+        // each other
+        if include_guard(&source).map_or(false, |guard| self.defines.contains(guard)) {
+            return Ok(());
+        }
+
+        if self.include_stack.iter().any(|included| included == file) {
+            Err(format!(
+                "Recursive include of {}, included from {}",
+                file,
+                self.include_stack.join(" <- ")
+            ))?
+        }
+
+        self.include_stack.push(file.to_string());
+        self.output += &line_marker(1, file);
+
+        let mut conditionals: Vec<Conditional> = Vec::new();
+        for (index, line) in source.lines().enumerate() {
+            let active = conditionals.iter().all(|conditional| conditional.active);
+            let error = |message: &str| {
+                format!("{}/{}:{}: {}", SHADER_SOURCE_DIR, file, index + 1, message)
+            };
+
+            let (directive, argument) = match split_directive(line) {
+                Some(directive) => directive,
+                None => {
+                    // Skipped and directive lines stay as empty lines so line numbers still match
+                    if active {
+                        self.output += line;
+                    }
+                    self.output.push('\n');
+                    continue;
+                }
+            };
+
+            match directive {
+                "include" if active => {
+                    let included_file = argument
+                        .strip_prefix('"')
+                        .and_then(|argument| argument.strip_suffix('"'))
+                        .ok_or_else(|| error("Expected #include \"file.wgsl\""))?;
+
+                    self.preprocess_file(included_file)
+                        .map_err(|e| format!("{}\n{}", e, error("Included from here")))?;
+                    self.output += &line_marker(index + 2, file);
+                    continue;
+                }
+                "define" if active => {
+                    self.defines.insert(argument.to_string());
+                }
+                "undef" if active => {
+                    self.defines.remove(argument);
+                }
+                "include" | "define" | "undef" => {}
+                "ifdef" | "ifndef" => conditionals.push(Conditional {
+                    active: self.defines.contains(argument) == (directive == "ifdef"),
+                    in_else: false,
+                }),
+                "else" => match conditionals.last_mut() {
+                    Some(conditional) if !conditional.in_else => {
+                        conditional.active = !conditional.active;
+                        conditional.in_else = true;
+                    }
+                    _ => Err(error("#else without #ifdef"))?,
+                },
+                "endif" => {
+                    conditionals
+                        .pop()
+                        .ok_or_else(|| error("#endif without #ifdef"))?;
+                }
+                _ => Err(error(&format!("Unknown directive #{}", directive)))?,
+            }
+
+            self.output.push('\n');
+        }
+
+        if !conditionals.is_empty() {
+            Err(format!("{}/{}: Missing #endif", SHADER_SOURCE_DIR, file))?
+        }
+
+        self.include_stack.pop();
+
+        Ok(())
+    }
+}
+
+// Resolves #include, #define, #undef, #ifdef, #ifndef, #else and #endif directives. `read_file`
+// gets include paths relative to `SHADER_SOURCE_DIR`.
+pub fn preprocess_shader<F>(file: &str, read_file: F) -> Result<String, String>
+where
+    F: FnMut(&str) -> Result<String, String>,
+{
+    let mut preprocessor = ShaderPreprocessor {
+        read_file,
+        defines: HashSet::new(),
+        include_stack: Vec::new(),
+        output: String::new(),
+    };
+
+    preprocessor.preprocess_file(file)?;
+
+    Ok(preprocessor.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(file: &str, files: &[(&str, &str)]) -> Result<String, String> {
+        preprocess_shader(file, |file| {
+            files
+                .iter()
+                .find(|(name, _)| *name == file)
+                .map(|(_, source)| source.to_string())
+                .ok_or(format!("File not found {}", file))
+        })
+    }
+
+    // Output without line markers and empty lines
+    fn code_lines(output: &str) -> Vec<&str> {
+        output
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with(LINE_MARKER_PREFIX))
+            .collect()
+    }
+
+    #[test]
+    fn include_is_surrounded_by_line_markers() {
+        let output = preprocess(
+            "main.wgsl",
+            &[
+                ("main.wgsl", "a\n#include \"inc.wgsl\"\nb\n"),
+                ("inc.wgsl", "x\ny\n"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            output,
+            line_marker(1, "main.wgsl")
+                + "a\n"
+                + &line_marker(1, "inc.wgsl")
+                + "x\ny\n"
+                + &line_marker(3, "main.wgsl")
+                + "b\n"
+        );
+    }
+
+    #[test]
+    fn directive_lines_stay_empty() {
+        let source = "#define A\n#ifdef A\na\n#else\nb\n#endif\n#ifndef A\nc\n#endif\nd\n";
+        let output = preprocess("main.wgsl", &[("main.wgsl", source)]).unwrap();
+
+        assert_eq!(
+            output,
+            line_marker(1, "main.wgsl") + "\n\na\n\n\n\n\n\n\nd\n"
+        );
+    }
+
+    #[test]
+    fn else_takes_the_other_branch() {
+        let source = "#ifdef A\na\n#else\nb\n#endif\n#ifndef A\nc\n#else\nd\n#endif\n";
+        let output = preprocess("main.wgsl", &[("main.wgsl", source)]).unwrap();
+
+        assert_eq!(code_lines(&output), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn nested_conditionals_need_every_branch_active() {
+        let source = "#define A\n#ifdef A\n#ifdef B\na\n#else\nb\n#endif\n#endif\n#ifdef B\n#ifdef A\nc\n#endif\n#endif\n";
+        let output = preprocess("main.wgsl", &[("main.wgsl", source)]).unwrap();
+
+        assert_eq!(code_lines(&output), vec!["b"]);
+    }
+
+    #[test]
+    fn undef_removes_defines() {
+        let source = "#define A\n#undef A\n#ifdef A\na\n#endif\n";
+        let output = preprocess("main.wgsl", &[("main.wgsl", source)]).unwrap();
+
+        assert!(code_lines(&output).is_empty());
+    }
+
+    #[test]
+    fn defines_in_inactive_branches_are_skipped() {
+        let source =
+            "#ifdef A\n#define B\n#include \"missing.wgsl\"\n#endif\n#ifdef B\nb\n#endif\n";
+        let output = preprocess("main.wgsl", &[("main.wgsl", source)]).unwrap();
+
+        assert!(code_lines(&output).is_empty());
+    }
+
+    #[test]
+    fn defines_carry_over_from_includes() {
+        let output = preprocess(
+            "main.wgsl",
+            &[
+                ("main.wgsl", "#include \"inc.wgsl\"\n#ifdef A\na\n#endif\n"),
+                ("inc.wgsl", "#define A\n"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(code_lines(&output), vec!["a"]);
+    }
+
+    #[test]
+    fn guarded_files_are_included_once() {
+        let output = preprocess(
+            "main.wgsl",
+            &[
+                (
+                    "main.wgsl",
+                    "#include \"inc.wgsl\"\n#include \"inc.wgsl\"\nmain\n",
+                ),
+                ("inc.wgsl", "#ifndef INC\n#define INC\ninc\n#endif\n"),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(code_lines(&output), vec!["inc", "main"]);
+    }
+
+    #[test]
+    fn guarded_include_cycles_are_allowed() {
+        let output = preprocess(
+            "a.wgsl",
+            &[
+                (
+                    "a.wgsl",
+                    "#ifndef A\n#define A\n#include \"b.wgsl\"\na\n#endif\n",
+                ),
+                (
+                    "b.wgsl",
+                    "#ifndef B\n#define B\n#include \"a.wgsl\"\nb\n#endif\n",
+                ),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(code_lines(&output), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn unguarded_include_cycles_are_rejected() {
+        let error = preprocess(
+            "a.wgsl",
+            &[
+                ("a.wgsl", "#include \"b.wgsl\"\n"),
+                ("b.wgsl", "#include \"a.wgsl\"\n"),
+            ],
+        )
+        .unwrap_err();
+
+        assert!(error.starts_with("Recursive include of a.wgsl, included from a.wgsl <- b.wgsl"));
+    }
+
+    #[test]
+    fn partial_guards_dont_skip_files() {
+        assert_eq!(include_guard("#ifndef A\n#define A\n#endif\nb\n"), None);
+        assert_eq!(include_guard("#ifndef A\na\n#else\nb\n#endif\n"), None);
+        assert_eq!(include_guard("#ifdef A\na\n#endif\n"), None);
+        assert_eq!(
+            include_guard("\n#ifndef A\n#ifdef B\n#endif\n#endif\n\n"),
+            Some("A")
+        );
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line() {
+        let error = preprocess("main.wgsl", &[("main.wgsl", "a\n#else\n")]).unwrap_err();
+        assert_eq!(
+            error,
+            format!("{}/main.wgsl:2: #else without #ifdef", SHADER_SOURCE_DIR)
+        );
+
+        let error = preprocess("main.wgsl", &[("main.wgsl", "#ifdef A\n")]).unwrap_err();
+        assert_eq!(
+            error,
+            format!("{}/main.wgsl: Missing #endif", SHADER_SOURCE_DIR)
+        );
+
+        let error = preprocess("main.wgsl", &[("main.wgsl", "\n\n#pragma once\n")]).unwrap_err();
+        assert_eq!(
+            error,
+            format!(
+                "{}/main.wgsl:3: Unknown directive #pragma",
+                SHADER_SOURCE_DIR
+            )
+        );
+    }
+
+    #[test]
+    fn include_errors_show_where_they_were_included() {
+        let error = preprocess(
+            "main.wgsl",
+            &[
+                ("main.wgsl", "\n#include \"inc.wgsl\"\n"),
+                ("inc.wgsl", "#endif\n"),
+            ],
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error,
+            format!(
+                "{0}/inc.wgsl:1: #endif without #ifdef\n{0}/main.wgsl:2: Included from here",
+                SHADER_SOURCE_DIR
+            )
+        );
+    }
+}
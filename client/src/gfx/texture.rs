@@ -0,0 +1,215 @@
+use wgpu::util::DeviceExt;
+
+// Matches MORPH_TEXTURE_WIDTH in include/morphing.wgsl, the most WebGL2 guarantees
+pub const MORPH_TEXTURE_WIDTH: u32 = 2048;
+
+pub fn gltf_image_to_rgba8(image: &gltf::image::Data) -> Vec<u8> {
+    use gltf::image::Format;
+
+    let pixel_count = (image.width * image.height) as usize;
+    let mut pixels = Vec::with_capacity(pixel_count * 4);
+
+    match image.format {
+        Format::R8G8B8A8 => pixels.extend_from_slice(&image.pixels),
+        Format::R8G8B8 => {
+            for rgb in image.pixels.chunks(3) {
+                pixels.extend_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+            }
+        }
+        Format::B8G8R8A8 => {
+            for bgra in image.pixels.chunks(4) {
+                pixels.extend_from_slice(&[bgra[2], bgra[1], bgra[0], bgra[3]]);
+            }
+        }
+        Format::B8G8R8 => {
+            for bgr in image.pixels.chunks(3) {
+                pixels.extend_from_slice(&[bgr[2], bgr[1], bgr[0], 255]);
+            }
+        }
+        // One and two channel images are grayscale, with alpha in the second channel
+        Format::R8G8 => {
+            for la in image.pixels.chunks(2) {
+                pixels.extend_from_slice(&[la[0], la[0], la[0], la[1]]);
+            }
+        }
+        Format::R8 => {
+            for l in image.pixels.iter() {
+                pixels.extend_from_slice(&[*l, *l, *l, 255]);
+            }
+        }
+        // 16 bit images are truncated to their most significant byte
+        Format::R16G16B16A16 => {
+            for rgba in image.pixels.chunks(8) {
+                pixels.extend_from_slice(&[rgba[1], rgba[3], rgba[5], rgba[7]]);
+            }
+        }
+        Format::R16G16B16 => {
+            for rgb in image.pixels.chunks(6) {
+                pixels.extend_from_slice(&[rgb[1], rgb[3], rgb[5], 255]);
+            }
+        }
+        Format::R16G16 => {
+            for la in image.pixels.chunks(4) {
+                pixels.extend_from_slice(&[la[1], la[1], la[1], la[3]]);
+            }
+        }
+        Format::R16 => {
+            for l in image.pixels.chunks(2) {
+                pixels.extend_from_slice(&[l[1], l[1], l[1], 255]);
+            }
+        }
+    }
+
+    pixels
+}
+
+fn wrap_mode_to_address_mode(wrap_mode: gltf::texture::WrappingMode) -> wgpu::AddressMode {
+    match wrap_mode {
+        gltf::texture::WrappingMode::ClampToEdge => wgpu::AddressMode::ClampToEdge,
+        gltf::texture::WrappingMode::MirroredRepeat => wgpu::AddressMode::MirrorRepeat,
+        gltf::texture::WrappingMode::Repeat => wgpu::AddressMode::Repeat,
+    }
+}
+
+impl super::State {
+    pub fn create_rgba8_texture(
+        &self,
+        label: &str,
+        width: u32,
+        height: u32,
+        pixels: &[u8],
+        srgb: bool,
+    ) -> wgpu::Texture {
+        create_rgba8_texture(
+            &self.device,
+            &self.queue,
+            label,
+            width,
+            height,
+            pixels,
+            srgb,
+        )
+    }
+
+    pub fn create_morph_delta_texture(
+        &self,
+        label: &str,
+        deltas: &[[f32; 4]],
+    ) -> Result<wgpu::Texture, Box<dyn std::error::Error>> {
+        let height = (deltas.len() as u32 + MORPH_TEXTURE_WIDTH - 1) / MORPH_TEXTURE_WIDTH;
+        if height > self.device.limits().max_texture_dimension_2d {
+            Err(format!(
+                "Too many morph target deltas ({}): {}",
+                deltas.len(),
+                label
+            ))?
+        }
+
+        Ok(create_morph_delta_texture(
+            &self.device,
+            &self.queue,
+            label,
+            deltas,
+        ))
+    }
+
+    pub fn create_gltf_sampler(&self, sampler: &gltf::texture::Sampler) -> wgpu::Sampler {
+        use gltf::texture::{MagFilter, MinFilter};
+
+        let mag_filter = match sampler.mag_filter() {
+            Some(MagFilter::Nearest) => wgpu::FilterMode::Nearest,
+            Some(MagFilter::Linear) | None => wgpu::FilterMode::Linear,
+        };
+
+        let (min_filter, mipmap_filter) = match sampler.min_filter() {
+            Some(MinFilter::Nearest) | Some(MinFilter::NearestMipmapNearest) => {
+                (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest)
+            }
+            Some(MinFilter::NearestMipmapLinear) => {
+                (wgpu::FilterMode::Nearest, wgpu::FilterMode::Linear)
+            }
+            Some(MinFilter::LinearMipmapNearest) => {
+                (wgpu::FilterMode::Linear, wgpu::FilterMode::Nearest)
+            }
+            Some(MinFilter::Linear) | Some(MinFilter::LinearMipmapLinear) | None => {
+                (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear)
+            }
+        };
+
+        self.device.create_sampler(&wgpu::SamplerDescriptor {
+            label: sampler.name(),
+            address_mode_u: wrap_mode_to_address_mode(sampler.wrap_s()),
+            address_mode_v: wrap_mode_to_address_mode(sampler.wrap_t()),
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter,
+            min_filter,
+            mipmap_filter,
+            ..Default::default()
+        })
+    }
+}
+
+pub fn create_rgba8_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    label: &str,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+    srgb: bool,
+) -> wgpu::Texture {
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: if srgb {
+                wgpu::TextureFormat::Rgba8UnormSrgb
+            } else {
+                wgpu::TextureFormat::Rgba8Unorm
+            },
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        },
+        pixels,
+    )
+}
+
+// Morph target deltas are read with textureLoad, texel i is at (i % MORPH_TEXTURE_WIDTH,
+// i / MORPH_TEXTURE_WIDTH)
+pub fn create_morph_delta_texture(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    label: &str,
+    deltas: &[[f32; 4]],
+) -> wgpu::Texture {
+    let width = (deltas.len() as u32).clamp(1, MORPH_TEXTURE_WIDTH);
+    let height = (deltas.len() as u32 + width - 1) / width;
+
+    let mut texels = deltas.to_vec();
+    texels.resize((width * height).max(1) as usize, [0.0; 4]);
+
+    device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height: height.max(1),
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        },
+        bytemuck::cast_slice(&texels),
+    )
+}
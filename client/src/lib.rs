@@ -42,6 +42,11 @@ fn handle_result<T>(result: Result<T, Box<dyn std::error::Error>>) {
 async fn run() -> Result<(), Box<dyn std::error::Error>> {
     let (mut gfx_state, window_state) = gfx::init().await?;
 
+    #[cfg(all(not(target_family = "wasm"), debug_assertions))]
+    {
+        gfx_state.enable_shader_hot_reload()?;
+    }
+
     let window = window_state.window.unwrap();
     let event_loop = window_state.event_loop.unwrap();
 
@@ -71,6 +76,10 @@ async fn run() -> Result<(), Box<dyn std::error::Error>> {
                             },
                         ..
                     } => *control_flow = ControlFlow::Exit,
+                    WindowEvent::Resized(physical_size) => gfx_state.resize(*physical_size)?,
+                    WindowEvent::ScaleFactorChanged { new_inner_size, .. } => {
+                        gfx_state.resize(**new_inner_size)?
+                    }
                     _ => {}
                 },
                 _ => {}
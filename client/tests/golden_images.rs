@@ -0,0 +1,439 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use matono_client::gfx::{
+    self,
+    bindgroup::BindGroupLayoutKey,
+    camera::{Camera, Lighting},
+    capture::CapturedImage,
+    debugdraw::RenderTest,
+    meshprocessing::{MeshProcessingOptions, NormalGeneration},
+    model::Model,
+    renderpass::{RenderPass, RenderPassFrameState},
+    rendertarget::{ColorRenderTargetKey, DepthRenderTargetKey, RenderTargetKey},
+    shader::{
+        PixelShaderKey, ShaderEntrypoints, ShaderKey, ShaderModuleDescriptor, VertexShaderKey,
+    },
+    State,
+};
+use winit::dpi::PhysicalSize;
+
+// Set to regenerate the reference images instead of comparing against them
+const UPDATE_GOLDEN_ENV: &str = "MATONO_UPDATE_GOLDEN";
+
+const GOLDEN_WIDTH: u32 = 256;
+const GOLDEN_HEIGHT: u32 = 256;
+
+// Maximum per-channel difference before a pixel counts as mismatched. Software rasterizers differ
+// slightly in how they round, so allow a bit of slack.
+const CHANNEL_TOLERANCE: u8 = 2;
+
+// Frames are skipped while shaders compile, give up if they never finish
+const RENDER_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden")
+}
+
+fn diff_dir() -> PathBuf {
+    Path::new(env!("CARGO_TARGET_TMPDIR")).join("golden")
+}
+
+// Software adapters don't cope well with several devices rendering at once
+static GPU_LOCK: Mutex<()> = Mutex::new(());
+
+async fn init_state() -> (MutexGuard<'static, ()>, State) {
+    let gpu_lock = GPU_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    // Asset and shader paths are relative to the workspace root
+    std::env::set_current_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("..")).unwrap();
+
+    let state = gfx::init_headless(PhysicalSize::new(GOLDEN_WIDTH, GOLDEN_HEIGHT))
+        .await
+        .unwrap();
+
+    (gpu_lock, state)
+}
+
+// Draws a glTF model with the model shaders, with everything the golden tests vary exposed
+struct ModelTest {
+    render_pass: RenderPass,
+    model: Model,
+    vs: VertexShaderKey,
+    ps: PixelShaderKey,
+    camera: Camera,
+    lighting: Lighting,
+    // Seconds into the model's first animation, if it has any
+    animation_time: f32,
+    mesh_processing: MeshProcessingOptions,
+}
+
+impl Default for ModelTest {
+    fn default() -> Self {
+        Self {
+            render_pass: RenderPass::new("ModelTest"),
+            model: Default::default(),
+            vs: VertexShaderKey::GltfSkinnedVS,
+            ps: PixelShaderKey::GltfPS,
+            camera: Camera {
+                eye: na::Point3::new(1.5, 1.25, 2.0),
+                ..Default::default()
+            },
+            lighting: Default::default(),
+            animation_time: 0.0,
+            mesh_processing: Default::default(),
+        }
+    }
+}
+
+impl ModelTest {
+    async fn prep(&mut self, path: &str, state: &State) -> Result<(), Box<dyn std::error::Error>> {
+        self.render_pass.vs = self.vs;
+        self.render_pass.ps = self.ps;
+        self.render_pass
+            .color_render_targets
+            .push(ColorRenderTargetKey::Window);
+        self.render_pass.depth_render_target = DepthRenderTargetKey::Window;
+        self.render_pass.bind_group_layouts = vec![
+            BindGroupLayoutKey::World,
+            BindGroupLayoutKey::Model,
+            BindGroupLayoutKey::Material,
+            BindGroupLayoutKey::Skin,
+        ];
+
+        self.model =
+            Model::from_gltf_with_options(path, self.render_pass.vs, &self.mesh_processing, state)
+                .await?;
+        self.render_pass.pipeline_variants = self.model.pipeline_variants();
+
+        Ok(())
+    }
+
+    fn frame(&mut self, state: &State, encoder: &mut wgpu::CommandEncoder) {
+        state.update_world_params(&self.camera, &self.lighting);
+
+        if !self.model.animations.is_empty() {
+            self.model.animate(0, self.animation_time);
+            self.model.update_world_transforms();
+            self.model.update_bind_groups(state);
+        }
+
+        let mut render_pass_frame_state = RenderPassFrameState::new();
+        let mut render_pass =
+            self.render_pass
+                .begin_frame(&mut render_pass_frame_state, state, encoder);
+
+        self.model.draw(state, &self.camera, &mut render_pass);
+    }
+}
+
+async fn render_frame<F>(state: &mut State, mut frame_func: F) -> CapturedImage
+where
+    F: FnMut(&mut State, &mut wgpu::CommandEncoder),
+{
+    let mut capture = None;
+    let start = Instant::now();
+
+    while capture.is_none() {
+        assert!(
+            start.elapsed() < RENDER_TIMEOUT,
+            "No frame was rendered within {:?}, shaders are still compiling",
+            RENDER_TIMEOUT
+        );
+
+        gfx::do_frame(state, |state, encoder| {
+            frame_func(state, encoder);
+            capture = Some(
+                state
+                    .capture_render_target(ColorRenderTargetKey::Window, encoder)
+                    .unwrap(),
+            );
+        })
+        .unwrap();
+
+        if capture.is_none() {
+            // Shaders are still compiling
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    state.read_capture(capture.unwrap()).await.unwrap()
+}
+
+fn diff_images(actual: &CapturedImage, expected: &CapturedImage) -> (u32, CapturedImage) {
+    let mut mismatched_pixels = 0;
+    let mut diff = CapturedImage {
+        width: actual.width,
+        height: actual.height,
+        pixels: Vec::with_capacity(actual.pixels.len()),
+    };
+
+    for (actual_pixel, expected_pixel) in actual.pixels.chunks(4).zip(expected.pixels.chunks(4)) {
+        let is_mismatch = actual_pixel
+            .iter()
+            .zip(expected_pixel)
+            .any(|(a, e)| a.abs_diff(*e) > CHANNEL_TOLERANCE);
+
+        if is_mismatch {
+            mismatched_pixels += 1;
+            diff.pixels.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            // Dimmed copy of the image so the mismatches stand out
+            let luma =
+                (actual_pixel[0] as u32 + actual_pixel[1] as u32 + actual_pixel[2] as u32) / 3 / 4;
+            diff.pixels
+                .extend_from_slice(&[luma as u8, luma as u8, luma as u8, 255]);
+        }
+    }
+
+    (mismatched_pixels, diff)
+}
+
+fn check_golden(name: &str, actual: &CapturedImage) {
+    let golden_path = golden_dir().join(format!("{}.png", name));
+
+    if std::env::var_os(UPDATE_GOLDEN_ENV).is_some() {
+        std::fs::create_dir_all(golden_dir()).unwrap();
+        actual.save_png(golden_path.to_str().unwrap()).unwrap();
+        return;
+    }
+
+    std::fs::create_dir_all(diff_dir()).unwrap();
+    let actual_path = diff_dir().join(format!("{}.actual.png", name));
+    let diff_path = diff_dir().join(format!("{}.diff.png", name));
+
+    let expected = match std::fs::read(&golden_path) {
+        Ok(png_bytes) => CapturedImage::decode_png(&png_bytes).unwrap(),
+        Err(_) => {
+            actual.save_png(actual_path.to_str().unwrap()).unwrap();
+            panic!(
+                "Missing reference image {}, rendered image written to {}. Run with {}=1 to accept it.",
+                golden_path.display(),
+                actual_path.display(),
+                UPDATE_GOLDEN_ENV
+            );
+        }
+    };
+
+    assert_eq!(
+        (actual.width, actual.height),
+        (expected.width, expected.height),
+        "Reference image {} has a different size",
+        golden_path.display()
+    );
+
+    let (mismatched_pixels, diff) = diff_images(actual, &expected);
+    if mismatched_pixels > 0 {
+        actual.save_png(actual_path.to_str().unwrap()).unwrap();
+        diff.save_png(diff_path.to_str().unwrap()).unwrap();
+        panic!(
+            "{} pixels differ from reference image {}, see {} and {}",
+            mismatched_pixels,
+            golden_path.display(),
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+// Renders the model at `path` and compares it against the reference image `name`. `setup`
+// configures the state and the test before the model is loaded.
+async fn check_model_golden<F>(name: &str, path: &str, setup: F)
+where
+    F: FnOnce(&mut State, &mut ModelTest),
+{
+    let (_gpu_lock, mut state) = init_state().await;
+
+    let mut model_test = ModelTest::default();
+    setup(&mut state, &mut model_test);
+    model_test.prep(path, &state).await.unwrap();
+
+    let image = render_frame(&mut state, |state, encoder| {
+        model_test.frame(state, encoder)
+    })
+    .await;
+
+    check_golden(name, &image);
+}
+
+#[tokio::test]
+async fn render_test() {
+    let (_gpu_lock, mut state) = init_state().await;
+
+    let mut render_test = RenderTest::default();
+    render_test.prep(&state).await.unwrap();
+
+    let image = render_frame(&mut state, |state, encoder| {
+        render_test.frame(state, encoder)
+    })
+    .await;
+
+    check_golden("render_test", &image);
+}
+
+#[tokio::test]
+async fn model_box() {
+    check_model_golden("model_box", "data/testmodels/Box.glb", |_, _| {}).await;
+}
+
+#[tokio::test]
+async fn model_box_msaa() {
+    check_model_golden("model_box_msaa", "data/testmodels/Box.glb", |state, _| {
+        state.set_sample_count(4).unwrap();
+    })
+    .await;
+}
+
+#[tokio::test]
+async fn model_skinned_strip() {
+    check_model_golden(
+        "model_skinned_strip",
+        "data/testmodels/SkinnedStrip.glb",
+        |_, model_test| {
+            // Halfway through the bend
+            model_test.animation_time = 0.5;
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn model_morph_quads() {
+    check_model_golden(
+        "model_morph_quads",
+        "data/testmodels/MorphQuads.glb",
+        |_, model_test| {
+            // Both morph targets at half weight
+            model_test.animation_time = 1.0;
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn model_primitive_modes() {
+    check_model_golden(
+        "model_primitive_modes",
+        "data/testmodels/PrimitiveModes.glb",
+        |_, model_test| {
+            // Straight on, so the lines and points line up with pixels
+            model_test.camera.eye = na::Point3::new(0.0, 0.0, 2.5);
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn model_pbr_materials() {
+    // Grayscale textures, occlusion from TEXCOORD_1, a double sided quad seen from behind, and two
+    // blended quads over an opaque one. The blended quads come first in node order, front to back.
+    check_model_golden(
+        "model_pbr_materials",
+        "data/testmodels/PbrMaterials.glb",
+        |_, model_test| {
+            model_test.vs = VertexShaderKey::PbrVS;
+            model_test.ps = PixelShaderKey::PbrPS;
+            model_test.camera.eye = na::Point3::new(0.0, 0.0, 3.5);
+            // Occlusion only darkens the ambient light
+            model_test.lighting.ambient_color = [0.5, 0.5, 0.5];
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn model_flat_normals() {
+    // The pyramid has no normals, glTF asks for flat ones
+    check_model_golden(
+        "model_flat_normals",
+        "data/testmodels/Pyramid.glb",
+        |_, _| {},
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn model_smooth_welded_normals() {
+    check_model_golden(
+        "model_smooth_welded_normals",
+        "data/testmodels/Pyramid.glb",
+        |_, model_test| {
+            model_test.mesh_processing = MeshProcessingOptions {
+                normals: NormalGeneration::Smooth,
+                weld_vertices: true,
+                ..Default::default()
+            };
+        },
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn broken_shader_uses_fallback() {
+    let (_gpu_lock, mut state) = init_state().await;
+
+    state
+        .add_shader_module(
+            ShaderModuleDescriptor {
+                path: "client/tests/shaders/broken.wgsl".to_string(),
+                constants: vec![],
+            },
+            &[
+                ShaderEntrypoints::VS((VertexShaderKey::Other(1), "vs_broken")),
+                ShaderEntrypoints::PS((PixelShaderKey::Other(1), "ps_broken")),
+            ],
+        )
+        .await
+        .unwrap();
+
+    for shader_key in [
+        ShaderKey::VS(VertexShaderKey::Other(1)),
+        ShaderKey::PS(PixelShaderKey::Other(1)),
+    ] {
+        assert!(!state.is_shader_ready(shader_key));
+        assert!(state.find_shader_compile_error(shader_key).is_some());
+    }
+    assert_eq!(
+        state.find_vertex_shader_stride(VertexShaderKey::Other(1)),
+        None
+    );
+
+    let mut model_test = ModelTest::default();
+    model_test.vs = VertexShaderKey::Other(1);
+    let error = model_test
+        .prep("data/testmodels/Box.glb", &state)
+        .await
+        .unwrap_err();
+    assert!(error
+        .to_string()
+        .starts_with("Vertex shader Other(1) failed to load"));
+}
+
+#[tokio::test]
+async fn invalid_render_targets_are_errors() {
+    let (_gpu_lock, mut state) = init_state().await;
+
+    gfx::do_frame(&mut state, |state, encoder| {
+        for key in [
+            ColorRenderTargetKey::Invalid,
+            ColorRenderTargetKey::Other(1),
+        ] {
+            assert!(state.capture_render_target(key, encoder).is_err());
+        }
+    })
+    .unwrap();
+
+    // Only user-defined render targets can be removed
+    assert!(state
+        .remove_render_target(RenderTargetKey::Color(ColorRenderTargetKey::Window))
+        .is_err());
+    assert!(state
+        .remove_render_target(RenderTargetKey::Color(ColorRenderTargetKey::Other(1)))
+        .is_err());
+    assert!(state.has_render_target(RenderTargetKey::Color(ColorRenderTargetKey::Window)));
+}
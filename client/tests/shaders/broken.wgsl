@@ -0,0 +1,10 @@
+// Fails to parse, for testing the fallback shaders
+@vertex
+fn vs_broken() -> @builtin(position) vec4<f32> {
+    return vec4<f32>(0.0, 0.0, 0.0, 1.0)
+}
+
+@fragment
+fn ps_broken() -> @location(0) vec4<f32> {
+    return vec4<f32>(1.0);
+}